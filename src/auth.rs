@@ -0,0 +1,351 @@
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use log::{debug, error, info, warn};
+use reqwest::header::HeaderValue;
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Mutex;
+use crate::client::{fetch_json_without_authenticator, observe, GitHubClient};
+use crate::error::GitHubError;
+use crate::now;
+
+/// Installation tokens are renewed this long before they expire, so that no request is sent with
+/// a token that expires in flight.
+const TOKEN_REFRESH_MARGIN_MINUTES: i64 = 5;
+
+/// How often the token file is checked for a rotated token.
+const TOKEN_FILE_POLL_INTERVAL_SECONDS: u64 = 10;
+
+/// Authentication as GitHub App instead of with a personal access token.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    /// The id of the GitHub App (`GITHUB_APP_ID`)
+    pub app_id: u64,
+    /// The id of the installation of the app in the organization (`GITHUB_APP_INSTALLATION_ID`)
+    pub installation_id: u64,
+    /// PEM file with the private key of the app (`GITHUB_APP_PRIVATE_KEY_PATH`)
+    pub private_key_path: PathBuf,
+}
+
+/// Provides the `Authorization` header of every request to github.
+#[derive(Clone)]
+pub struct Authenticator {
+    credentials: Arc<Credentials>,
+}
+
+enum Credentials {
+    Token(HeaderValue),
+    TokenFile(TokenFile),
+    App(Box<App>),
+}
+
+/// A token mounted as file, e.g. a Docker or Kubernetes secret, which is replaced once the file
+/// contains a different token.
+struct TokenFile {
+    path: PathBuf,
+    authorization: RwLock<HeaderValue>,
+}
+
+struct App {
+    app_id: u64,
+    installation_id: u64,
+    key: EncodingKey,
+    token: Mutex<Option<InstallationToken>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct InstallationToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+struct Claims {
+    iat: i64,
+    exp: i64,
+    iss: String,
+}
+
+impl Debug for Authenticator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Never print the token or the key
+        match self.credentials.as_ref() {
+            Credentials::Token(_) => { write!(f, "Authenticator(Token)") }
+            Credentials::TokenFile(token_file) => { write!(f, "Authenticator(TokenFile {path})", path = token_file.path.display()) }
+            Credentials::App(app) => { write!(f, "Authenticator(App {id})", id = app.app_id) }
+        }
+    }
+}
+
+impl Authenticator {
+    pub fn from_token(token: &str) -> anyhow::Result<Authenticator> {
+        Ok(Authenticator { credentials: Arc::new(Credentials::Token(bearer(token)?)) })
+    }
+
+    pub fn from_token_file(path: &Path) -> anyhow::Result<Authenticator> {
+        let token_file = TokenFile {
+            path: path.to_path_buf(),
+            authorization: RwLock::new(read_token_file(path)?),
+        };
+        Ok(Authenticator { credentials: Arc::new(Credentials::TokenFile(token_file)) })
+    }
+
+    /// Reads the private key of the app, the installation token is requested on first use.
+    pub fn from_app(config: &AppConfig) -> anyhow::Result<Authenticator> {
+        let path = &config.private_key_path;
+        let pem = fs::read(path)
+            .map_err(|e| anyhow!("Failed to read the private key of the GitHub App '{path}': {e}", path = path.display()))?;
+        let key = EncodingKey::from_rsa_pem(&pem)
+            .map_err(|e| anyhow!("Invalid private key of the GitHub App '{path}': {e}", path = path.display()))?;
+        let app = App {
+            app_id: config.app_id,
+            installation_id: config.installation_id,
+            key,
+            token: Mutex::new(None),
+        };
+        Ok(Authenticator { credentials: Arc::new(Credentials::App(Box::new(app))) })
+    }
+
+    /// The value of the `Authorization` header, renewing the installation token of an app if it is
+    /// about to expire. The token is requested with the `github` client like any other request.
+    pub async fn get_authorization(&self, github: &GitHubClient) -> Result<HeaderValue, GitHubError> {
+        match self.credentials.as_ref() {
+            Credentials::Token(authorization) => { Ok(authorization.clone()) }
+            Credentials::TokenFile(token_file) => {
+                match token_file.authorization.read() {
+                    Ok(authorization) => { Ok(authorization.clone()) }
+                    Err(_) => { Err(GitHubError::Authentication(String::from("Failed to acquire lock of the token"))) }
+                }
+            }
+            Credentials::App(app) => { app.get_authorization(github).await }
+        }
+    }
+
+    /// Re-reads a token file periodically and whenever the process receives SIGHUP, so that a
+    /// rotated token is picked up without a restart. Other credentials are left alone.
+    pub fn watch_token_file(&self) -> anyhow::Result<()> {
+        if !matches!(self.credentials.as_ref(), Credentials::TokenFile(_)) {
+            return Ok(());
+        }
+        let mut hangup_stream = signal(SignalKind::hangup())?;
+        let credentials = self.credentials.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(TOKEN_FILE_POLL_INTERVAL_SECONDS));
+            loop {
+                tokio::select! {
+                    _ = hangup_stream.recv() => {}
+                    _ = interval.tick() => {}
+                };
+                if let Credentials::TokenFile(token_file) = credentials.as_ref() {
+                    token_file.reload();
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl TokenFile {
+    /// Replaces the token if the file contains a different one. The content is compared instead of
+    /// the modification time, as the symlink swap of a mounted Kubernetes secret does not
+    /// necessarily change it. A file that cannot be read, e.g. while the secret is being rotated,
+    /// keeps the previous token in use.
+    fn reload(&self) {
+        let loaded = match read_token_file(&self.path) {
+            Ok(loaded) => { loaded }
+            Err(e) => {
+                warn!("Keeping the current token: {e}");
+                return;
+            }
+        };
+        match self.authorization.write() {
+            Ok(mut authorization) if *authorization != loaded => {
+                *authorization = loaded;
+                info!("Reloaded the github-token from '{path}'", path = self.path.display());
+            }
+            Ok(_) => { debug!("The github-token in '{path}' is unchanged", path = self.path.display()); }
+            Err(_) => { error!("Failed to acquire lock of the token!"); }
+        }
+    }
+}
+
+impl App {
+    async fn get_authorization(&self, github: &GitHubClient) -> Result<HeaderValue, GitHubError> {
+        // Holding the lock while refreshing makes concurrent requests wait for a single refresh
+        let mut token = self.token.lock().await;
+        let refresh_at = now() + Duration::minutes(TOKEN_REFRESH_MARGIN_MINUTES);
+        let is_valid = token.as_ref().map(|token| token.expires_at > refresh_at).unwrap_or(false);
+        if !is_valid {
+            *token = Some(self.request_installation_token(github).await?);
+        }
+        let token = token.as_ref().expect("Installation token has just been set");
+        bearer(&token.token).map_err(|e| GitHubError::Authentication(e.to_string()))
+    }
+
+    /// Retried and rate limited like every other request, failures count as api errors of the
+    /// `installation_token` endpoint.
+    async fn request_installation_token(&self, github: &GitHubClient) -> Result<InstallationToken, GitHubError> {
+        let url = format!("{api_url}/app/installations/{id}/access_tokens", api_url = github.api_url, id = self.installation_id);
+        debug!("Requesting installation token of GitHub App {id}", id = self.app_id);
+        let request = github.client.post(url).bearer_auth(self.create_jwt()?);
+        let token: InstallationToken = observe("installation_token", fetch_json_without_authenticator(github, request).await)?;
+        debug!("Installation token of GitHub App {id} expires at {expires_at}", id = self.app_id, expires_at = token.expires_at);
+        Ok(token)
+    }
+
+    /// The JSON web token identifying the app, valid for the maximum of ten minutes. It is issued
+    /// a minute in the past to allow for clock drift between us and github.
+    fn create_jwt(&self) -> Result<String, GitHubError> {
+        let now = now();
+        let claims = Claims {
+            iat: (now - Duration::seconds(60)).timestamp(),
+            exp: (now + Duration::minutes(9)).timestamp(),
+            iss: self.app_id.to_string(),
+        };
+        jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.key)
+            .map_err(|e| GitHubError::Authentication(format!("Failed to sign the JSON web token: {e}")))
+    }
+}
+
+fn read_token_file(path: &Path) -> anyhow::Result<HeaderValue> {
+    let token = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read the token file '{path}': {e}", path = path.display()))?;
+    if token.trim().is_empty() {
+        return Err(anyhow!("The token file '{path}' is empty", path = path.display()));
+    }
+    bearer(token.trim())
+}
+
+fn bearer(token: &str) -> anyhow::Result<HeaderValue> {
+    let mut value = HeaderValue::from_str(format!("Bearer {token}").as_str())
+        .map_err(|_| anyhow!("The github-token contains characters that are not allowed in a header"))?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use jsonwebtoken::{DecodingKey, Validation};
+    use reqwest::StatusCode;
+    use serde_json::{json, Value};
+    use super::*;
+    use crate::config::Config;
+    use crate::fixture::{json_response, start_mock_server};
+
+    const PUBLIC_KEY: &str = include_str!("../tests/fixtures/app_public_key.pem");
+
+    fn app_config() -> AppConfig {
+        AppConfig {
+            app_id: 1234,
+            installation_id: 42,
+            private_key_path: Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/app_private_key.pem"),
+        }
+    }
+
+    fn client(api_url: String) -> GitHubClient {
+        let mut config = Config { api_url, token: String::from("unused"), ..Config::default() };
+        config.retry.base_delay_ms = 1;
+        GitHubClient::from(&config).expect("Invalid client config")
+    }
+
+    /// Serves installation tokens valid for `lifetime` after failing the first `failures` requests.
+    fn start_token_server(lifetime: Duration, failures: usize) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let api_url = start_mock_server(move |_, request| {
+            let index = counter.fetch_add(1, Ordering::SeqCst);
+            let is_signed = request.headers().get("Authorization")
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.starts_with("Bearer ey"))
+                .unwrap_or(false);
+            if request.uri().path() != "/app/installations/42/access_tokens" || !is_signed {
+                return json_response(StatusCode::NOT_FOUND, json!({ "message": "Not Found" }));
+            }
+            if index < failures {
+                return json_response(StatusCode::BAD_GATEWAY, json!({ "message": "Server Error" }));
+            }
+            json_response(StatusCode::CREATED, json!({ "token": format!("ghs_{index}"), "expires_at": now() + lifetime }))
+        });
+        (api_url, requests)
+    }
+
+    #[test]
+    fn picks_up_a_token_rotated_by_swapping_a_symlink() {
+        // Laid out like a mounted Kubernetes secret, whose files are symlinks into a directory that is swapped
+        let directory = std::env::temp_dir().join(format!("github-exporter-token-{pid}", pid = std::process::id()));
+        fs::create_dir_all(&directory).expect("Failed to create the secret directory");
+        let (first, second, current, path) = (directory.join("first"), directory.join("second"), directory.join("current"), directory.join("token"));
+        fs::write(&first, "ghp_first\n").expect("Failed to write the token");
+        fs::write(&second, "ghp_second\n").expect("Failed to write the token");
+        // Both tokens have the same modification time, so only the content tells them apart
+        let modified = fs::metadata(&first).and_then(|metadata| metadata.modified()).expect("No modification time");
+        fs::File::options().write(true).open(&second).and_then(|file| file.set_modified(modified)).expect("Failed to set the modification time");
+        std::os::unix::fs::symlink(&first, &current).expect("Failed to link the token");
+        std::os::unix::fs::symlink(&current, &path).expect("Failed to link the token");
+        let authenticator = Authenticator::from_token_file(&path).expect("Invalid token file");
+        let token_file = match authenticator.credentials.as_ref() {
+            Credentials::TokenFile(token_file) => { token_file }
+            _ => { panic!("Not authenticated with a token file") }
+        };
+        let authorization = || token_file.authorization.read().expect("Poisoned lock").clone();
+        assert_eq!(authorization(), "Bearer ghp_first");
+
+        let swapped = directory.join("swapped");
+        std::os::unix::fs::symlink(&second, &swapped).expect("Failed to link the token");
+        fs::rename(&swapped, &current).expect("Failed to swap the token");
+        token_file.reload();
+        assert_eq!(authorization(), "Bearer ghp_second");
+        // An empty file during the rotation keeps the current token
+        fs::write(&second, "").expect("Failed to write the token");
+        token_file.reload();
+        assert_eq!(authorization(), "Bearer ghp_second");
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn signs_a_jwt_issued_by_the_app() {
+        let authenticator = Authenticator::from_app(&app_config()).expect("Invalid app config");
+        let jwt = match authenticator.credentials.as_ref() {
+            Credentials::App(app) => { app.create_jwt().expect("Failed to sign the JWT") }
+            _ => { panic!("Not authenticated as app") }
+        };
+        let key = DecodingKey::from_rsa_pem(PUBLIC_KEY.as_bytes()).expect("Invalid public key");
+        let claims = jsonwebtoken::decode::<Value>(&jwt, &key, &Validation::new(Algorithm::RS256)).expect("Invalid JWT").claims;
+        assert_eq!(claims["iss"], json!("1234"));
+        let (iat, exp) = (claims["iat"].as_i64().expect("No iat"), claims["exp"].as_i64().expect("No exp"));
+        assert_eq!(exp - iat, 10 * 60);
+        assert!(iat <= (now() - Duration::seconds(59)).timestamp());
+    }
+
+    #[tokio::test]
+    async fn reuses_the_installation_token_until_shortly_before_it_expires() {
+        let (api_url, requests) = start_token_server(Duration::hours(1), 0);
+        let authenticator = Authenticator::from_app(&app_config()).expect("Invalid app config");
+        let github = client(api_url);
+        assert_eq!(authenticator.get_authorization(&github).await.expect("No token"), "Bearer ghs_0");
+        assert_eq!(authenticator.get_authorization(&github).await.expect("No token"), "Bearer ghs_0");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let (api_url, requests) = start_token_server(Duration::minutes(TOKEN_REFRESH_MARGIN_MINUTES - 1), 0);
+        let authenticator = Authenticator::from_app(&app_config()).expect("Invalid app config");
+        let github = client(api_url);
+        assert_eq!(authenticator.get_authorization(&github).await.expect("No token"), "Bearer ghs_0");
+        assert_eq!(authenticator.get_authorization(&github).await.expect("No token"), "Bearer ghs_1");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn retries_the_installation_token_request_on_server_errors() {
+        let (api_url, requests) = start_token_server(Duration::hours(1), 1);
+        let authenticator = Authenticator::from_app(&app_config()).expect("Invalid app config");
+        assert_eq!(authenticator.get_authorization(&client(api_url)).await.expect("No token"), "Bearer ghs_1");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,160 @@
+use std::borrow::Cow;
+use anyhow::anyhow;
+use regex::RegexSet;
+use serde::Deserialize;
+use crate::data::{Commit, RepositoriesWithCommits, RepositoryAndCommits};
+
+/// How commits of bots like dependabot, renovate or CI pipelines show up in the metrics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BotMode {
+    /// Bot commits are counted like any other commit
+    #[default]
+    Include,
+    /// Bot commits are not counted at all
+    Exclude,
+    /// Every commit based metric gets an `is_bot` label separating bot commits from the others
+    Label,
+}
+
+/// Which commits are made by bots. Accounts of the github type `Bot` and logins or git author
+/// names ending with `[bot]` are always detected, the lists extend this for bots using regular accounts.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotConfig {
+    /// Whether bot commits are counted, dropped or labeled
+    pub mode: BotMode,
+    /// Further github logins of bots, compared case-insensitively
+    pub logins: Vec<String>,
+    /// Regular expressions matched against the git author email
+    pub email_patterns: Vec<String>,
+}
+
+/// The compiled form of a [BotConfig].
+#[derive(Debug, Clone)]
+pub struct BotDetector {
+    mode: BotMode,
+    logins: Vec<String>,
+    email_patterns: RegexSet,
+}
+
+impl Default for BotDetector {
+    fn default() -> Self {
+        BotDetector { mode: BotMode::default(), logins: Vec::new(), email_patterns: RegexSet::empty() }
+    }
+}
+
+impl BotDetector {
+    pub fn from(config: &BotConfig) -> anyhow::Result<BotDetector> {
+        let email_patterns = RegexSet::new(&config.email_patterns)
+            .map_err(|e| anyhow!("Invalid bot email pattern: {e}"))?;
+        Ok(BotDetector { mode: config.mode, logins: config.logins.clone(), email_patterns })
+    }
+
+    pub fn is_bot(&self, commit: &Commit) -> bool {
+        if let Some(author) = &commit.author {
+            if author.user_type.eq_ignore_ascii_case("Bot")
+                || author.login.ends_with("[bot]")
+                || self.logins.iter().any(|login| login.eq_ignore_ascii_case(&author.login)) {
+                return true;
+            }
+        }
+        match &commit.commit.author {
+            // Commits that are not linked to an account still carry the name of the bot
+            Some(author) => { author.name.ends_with("[bot]") || self.email_patterns.is_match(&author.email) }
+            None => { false }
+        }
+    }
+
+    /// The value of the `is_bot` label for commits of bots or others, `None` unless bots are labeled.
+    pub fn label(&self, is_bot: bool) -> Option<bool> {
+        match self.mode {
+            BotMode::Label => { Some(is_bot) }
+            BotMode::Include | BotMode::Exclude => { None }
+        }
+    }
+
+    /// Splits the data into the parts counted separately, each along with its `is_bot` label:
+    /// everything unlabeled if bots are included, only the commits of others if bots are excluded
+    /// and the commits of others and of bots separately if bots are labeled. Repositories stay in
+    /// every part even without commits, so that their counters are still exported.
+    pub fn partition<'a>(&self, data: &'a RepositoriesWithCommits) -> Vec<(Option<bool>, Cow<'a, RepositoriesWithCommits>)> {
+        match self.mode {
+            BotMode::Include => { vec![(None, Cow::Borrowed(data))] }
+            BotMode::Exclude => { vec![(None, Cow::Owned(self.select(data, false)))] }
+            BotMode::Label => {
+                vec![
+                    (Some(false), Cow::Owned(self.select(data, false))),
+                    (Some(true), Cow::Owned(self.select(data, true))),
+                ]
+            }
+        }
+    }
+
+    fn select(&self, data: &RepositoriesWithCommits, is_bot: bool) -> RepositoriesWithCommits {
+        let data = data.data.iter().map(|value| {
+            let commits = value.commits.iter()
+                .filter(|commit| self.is_bot(&commit.commit) == is_bot)
+                .cloned()
+                .collect();
+            RepositoryAndCommits::from(value.repository.clone(), commits)
+        }).collect();
+        RepositoriesWithCommits { data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::{commit, repository};
+
+    fn detector(mode: BotMode) -> BotDetector {
+        let config = BotConfig {
+            mode,
+            logins: vec![String::from("ci-runner")],
+            email_patterns: vec![String::from(r"^deploy@.*\.internal$")],
+        };
+        BotDetector::from(&config).expect("Invalid bot config")
+    }
+
+    #[test]
+    fn detects_bots_by_type_login_name_and_email() {
+        let detector = detector(BotMode::Exclude);
+        let human = commit("h1", "2023-05-20T10:00:00Z", 1, 0).commit;
+        assert!(!detector.is_bot(&human));
+        let mut app = human.clone();
+        app.author.as_mut().expect("Commit without author").user_type = String::from("Bot");
+        assert!(detector.is_bot(&app));
+        let mut dependabot = human.clone();
+        dependabot.author.as_mut().expect("Commit without author").login = String::from("dependabot[bot]");
+        assert!(detector.is_bot(&dependabot));
+        let mut configured = human.clone();
+        configured.author.as_mut().expect("Commit without author").login = String::from("CI-Runner");
+        assert!(detector.is_bot(&configured));
+        let mut renovate = human.clone();
+        renovate.author = None;
+        renovate.commit.author.as_mut().expect("Commit without git author").name = String::from("renovate[bot]");
+        assert!(detector.is_bot(&renovate));
+        let mut deploy = human.clone();
+        deploy.author = None;
+        deploy.commit.author.as_mut().expect("Commit without git author").email = String::from("deploy@build.internal");
+        assert!(detector.is_bot(&deploy));
+    }
+
+    #[test]
+    fn partitions_commits_by_mode() {
+        let mut bot = commit("b1", "2023-05-21T10:00:00Z", 100, 50);
+        bot.commit.author.as_mut().expect("Commit without author").login = String::from("dependabot[bot]");
+        let data = RepositoriesWithCommits {
+            data: vec![RepositoryAndCommits::from(repository(1, "octo-org", "exporter"), vec![commit("h1", "2023-05-20T10:00:00Z", 1, 0), bot])],
+        };
+        let shas = |detector: &BotDetector| -> Vec<(Option<bool>, Vec<String>)> {
+            detector.partition(&data).into_iter()
+                .map(|(is_bot, part)| (is_bot, part.data[0].commits.iter().map(|commit| commit.commit.sha.clone()).collect()))
+                .collect()
+        };
+        assert_eq!(shas(&detector(BotMode::Include)), vec![(None, vec![String::from("h1"), String::from("b1")])]);
+        assert_eq!(shas(&detector(BotMode::Exclude)), vec![(None, vec![String::from("h1")])]);
+        assert_eq!(shas(&detector(BotMode::Label)), vec![(Some(false), vec![String::from("h1")]), (Some(true), vec![String::from("b1")])]);
+    }
+}
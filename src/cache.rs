@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use log::{debug, error};
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::RequestBuilder;
+
+/// A successful response of a list endpoint, reused when github answers the conditional request
+/// for the same url with `304 Not Modified`.
+#[derive(Debug)]
+pub struct CachedResponse {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    pub body: String,
+    pub next_page: Option<String>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    response: Arc<CachedResponse>,
+    is_used: bool,
+}
+
+/// Remembers the responses per url, so that unchanged lists are neither downloaded again nor
+/// counted against the rate limit, as github does not count `304 Not Modified` responses.
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl CachedResponse {
+    /// Adds the validators of the cached response, turning the request into a conditional one.
+    pub fn apply(&self, mut request: RequestBuilder) -> RequestBuilder {
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        request
+    }
+}
+
+impl ResponseCache {
+    pub fn get(&self, url: &str) -> Option<Arc<CachedResponse>> {
+        let mut entries = match self.entries.lock() {
+            Ok(guard) => { guard }
+            Err(_) => {
+                error!("Failed to acquire mutex guard of the response cache!");
+                return None;
+            }
+        };
+        let entry = entries.get_mut(url)?;
+        entry.is_used = true;
+        Some(entry.response.clone())
+    }
+
+    /// Caches the body if github sent a validator along, which is needed for the conditional request.
+    pub fn insert(&self, url: String, headers: &HeaderMap, body: String, next_page: Option<String>) {
+        let etag = get_header(headers, ETAG);
+        let last_modified = get_header(headers, LAST_MODIFIED);
+        let mut entries = match self.entries.lock() {
+            Ok(guard) => { guard }
+            Err(_) => {
+                error!("Failed to acquire mutex guard of the response cache!");
+                return;
+            }
+        };
+        if etag.is_none() && last_modified.is_none() {
+            entries.remove(&url);
+            return;
+        }
+        let response = CachedResponse { etag, last_modified, body, next_page };
+        entries.insert(url, CacheEntry { response: Arc::new(response), is_used: true });
+    }
+
+    /// Drops every entry that has not been used since the last call, e.g. the commit lists of a
+    /// watermark that has advanced since, so that the cache does not grow with every sync.
+    pub fn prune(&self) {
+        let mut entries = match self.entries.lock() {
+            Ok(guard) => { guard }
+            Err(_) => {
+                error!("Failed to acquire mutex guard of the response cache!");
+                return;
+            }
+        };
+        entries.retain(|_, entry| entry.is_used);
+        entries.values_mut().for_each(|entry| entry.is_used = false);
+        debug!("{count} responses remain cached", count = entries.len());
+    }
+}
+
+fn get_header(headers: &HeaderMap, name: reqwest::header::HeaderName) -> Option<String> {
+    headers.get(name)?.to_str().ok().map(String::from)
+}
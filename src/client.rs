@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{debug, warn};
+use reqwest::{Certificate, Client, RequestBuilder, StatusCode};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, LINK};
+use serde::de::DeserializeOwned;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::auth::Authenticator;
+use crate::cache::ResponseCache;
+use crate::config::{Backend, Config, Target};
+use crate::data::*;
+use crate::error::{check_status, GitHubError};
+use crate::metrics::record_api_error;
+use crate::rate_limit::{get_rate_limit_delay, update_rate_limit, wait_for_rate_limit, CORE_RESOURCE, GRAPHQL_RESOURCE, MAX_RATE_LIMIT_ATTEMPTS};
+use crate::retry::{get_retry_after, is_transient_error, is_transient_failure, RetryConfig};
+use crate::source::GitHubSource;
+use crate::state::State;
+use crate::{graphql, source};
+
+/// Maximum page size supported by the github list endpoints.
+const PAGE_SIZE: u8 = 100;
+
+/// The http client and settings shared by all requests to github.
+#[derive(Debug, Clone)]
+pub struct GitHubClient {
+    pub(crate) client: Client,
+    headers: HeaderMap,
+    authenticator: Authenticator,
+    /// Bounds the number of requests in flight across all concurrently fetched repositories and commits
+    request_permits: Arc<Semaphore>,
+    pub(crate) max_concurrent_requests: usize,
+    retry: RetryConfig,
+    /// The base url of the REST api without trailing slash, e.g. `https://github.example.com/api/v3`
+    pub(crate) api_url: String,
+    pub(crate) graphql_url: String,
+    /// The responses of list endpoints, sent as conditional requests on the next sync
+    cache: Arc<ResponseCache>,
+    backend: Backend,
+}
+
+impl GitHubClient {
+    pub fn from(config: &Config) -> anyhow::Result<GitHubClient> {
+        let mut builder = Client::builder();
+        if let Some(ca_bundle) = &config.ca_bundle {
+            for certificate in load_ca_bundle(ca_bundle)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        let client = builder.build()?;
+        let headers = create_default_headers();
+        let api_url = config.api_url.trim_end_matches('/').to_string();
+        let authenticator = match (&config.app, &config.token_file) {
+            (Some(app), _) => { Authenticator::from_app(app)? }
+            (None, Some(token_file)) => { Authenticator::from_token_file(token_file)? }
+            (None, None) => { Authenticator::from_token(&config.token)? }
+        };
+        Ok(GitHubClient {
+            client,
+            headers,
+            authenticator,
+            request_permits: Arc::new(Semaphore::new(config.max_concurrent_requests)),
+            max_concurrent_requests: config.max_concurrent_requests,
+            retry: config.retry.clone(),
+            api_url,
+            graphql_url: config.graphql_url(),
+            cache: Arc::new(ResponseCache::default()),
+            backend: config.backend,
+        })
+    }
+
+    /// Picks up a rotated token, if the token is read from a file.
+    pub fn watch_token_file(&self) -> anyhow::Result<()> {
+        self.authenticator.watch_token_file()
+    }
+}
+
+/// Reads every certificate of a PEM bundle, as the client only accepts one certificate at a time.
+fn load_ca_bundle(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let content = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read CA bundle '{path}': {e}", path = path.display()))?;
+    let certificates = content.match_indices(BEGIN)
+        .map(|(start, _)| {
+            let block = &content.as_bytes()[start..];
+            let end = content[start..].find(END).map(|end| end + END.len()).unwrap_or(block.len());
+            Certificate::from_pem(&block[..end])
+                .map_err(|e| anyhow!("Invalid certificate in CA bundle '{path}': {e}", path = path.display()))
+        })
+        .collect::<anyhow::Result<Vec<Certificate>>>()?;
+    if certificates.is_empty() {
+        return Err(anyhow!("CA bundle '{path}' does not contain any certificate", path = path.display()));
+    }
+    Ok(certificates)
+}
+
+#[async_trait]
+impl GitHubSource for GitHubClient {
+    async fn list_repositories(&self, target: &Target) -> Result<Vec<MinimalRepository>, GitHubError> {
+        list_repositories(self, target).await
+    }
+
+    async fn list_commits(&self, full_repository_name: &str, since: DateTime<Utc>) -> Result<Vec<Commit>, GitHubError> {
+        list_commits_in_repository_since(self, full_repository_name.to_string(), since).await
+    }
+
+    async fn get_commit(&self, full_repository_name: &str, sha: &str) -> Result<CommitChangeDetails, GitHubError> {
+        fetch_commit(self, full_repository_name, sha).await
+    }
+
+    fn max_concurrent_requests(&self) -> usize {
+        self.max_concurrent_requests
+    }
+
+    async fn get_repositories_and_commits_since(&self, repositories: Vec<MinimalRepository>, state: &State) -> Vec<(String, anyhow::Result<RepositoryAndCommits>)> {
+        match self.backend {
+            // The number of requests actually in flight is bounded by the request permits in send_request
+            Backend::Rest => { source::get_repositories_and_commits_since(self, repositories, state).await }
+            Backend::GraphQl => { graphql::get_repositories_and_commits_since(self, repositories, state).await }
+        }
+    }
+
+    fn sync_finished(&self) {
+        self.cache.prune();
+    }
+}
+
+async fn list_repositories(github: &GitHubClient, target: &Target) -> Result<Vec<MinimalRepository>, GitHubError> {
+    let result = match target {
+        Target::Organization(organization) => { list_organization_repositories(github, organization).await }
+        Target::User(user) => { list_user_repositories(github, user).await }
+    };
+    observe("list_repositories", result)
+}
+
+async fn list_organization_repositories(github: &GitHubClient, organization: &str) -> Result<Vec<MinimalRepository>, GitHubError> {
+    let url = format!("{api_url}/orgs/{organization}/repos", api_url = github.api_url, organization = organization);
+    let repositories = fetch_all_pages(github, url, &HashMap::new()).await?;
+    debug!("Retrieved {count} repositories of {org}", count = repositories.len(), org = organization);
+    Ok(repositories)
+}
+
+async fn list_user_repositories(github: &GitHubClient, user: &str) -> Result<Vec<MinimalRepository>, GitHubError> {
+    let url = format!("{api_url}/users/{user}/repos", api_url = github.api_url, user = user);
+    let repositories = fetch_all_pages(github, url, &HashMap::new()).await?;
+    debug!("Retrieved {count} repositories of {user}", count = repositories.len(), user = user);
+    Ok(repositories)
+}
+
+/// Counts failed requests per endpoint and kind of error.
+pub(crate) fn observe<Type>(endpoint: &str, result: Result<Type, GitHubError>) -> Result<Type, GitHubError> {
+    if let Err(error) = &result {
+        record_api_error(endpoint, error.kind());
+    }
+    result
+}
+
+/// Fetches every page of a github list endpoint by following the `rel="next"` links of the `Link` header.
+async fn fetch_all_pages<Type: DeserializeOwned>(github: &GitHubClient, url: String, params: &HashMap<&str, String>) -> Result<Vec<Type>, GitHubError> {
+    let mut items = Vec::new();
+    let mut request = github.client.get(url)
+        .query(params)
+        .query(&[("per_page", PAGE_SIZE)]);
+    loop {
+        let (page, next_page): (Vec<Type>, _) = fetch_json_cached(github, request).await?;
+        items.extend(page);
+        match next_page {
+            None => { break; }
+            // The next link already contains all query parameters of the original request
+            Some(next_page) => { request = github.client.get(next_page); }
+        }
+    }
+    Ok(items)
+}
+
+/// Sends the request and deserializes the response body, returning the url of the next page
+/// alongside for paginated endpoints.
+pub(crate) async fn fetch_json<Type: DeserializeOwned>(github: &GitHubClient, request: RequestBuilder) -> Result<(Type, Option<String>), GitHubError> {
+    let (response, _permit) = send_authorized_request(github, request).await?;
+    let next_page = get_next_page_url(response.headers());
+    let json_string = read_body(response).await?;
+    Ok((parse_json(&json_string)?, next_page))
+}
+
+/// Like [fetch_json], but sends a conditional request if the url has been fetched before and
+/// reuses the cached body if github answers with `304 Not Modified`.
+async fn fetch_json_cached<Type: DeserializeOwned>(github: &GitHubClient, request: RequestBuilder) -> Result<(Type, Option<String>), GitHubError> {
+    let url = match request.try_clone().and_then(|request| request.build().ok()) {
+        Some(built) => { built.url().to_string() }
+        None => { return fetch_json(github, request).await; }
+    };
+    let cached = github.cache.get(&url);
+    let request = match &cached {
+        Some(cached) => { cached.apply(request) }
+        None => { request }
+    };
+    let (response, _permit) = send_authorized_request(github, request).await?;
+    if let (StatusCode::NOT_MODIFIED, Some(cached)) = (response.status(), &cached) {
+        debug!("{url} has not been modified, using the cached response");
+        return Ok((parse_json(&cached.body)?, cached.next_page.clone()));
+    }
+    let headers = response.headers().clone();
+    let next_page = get_next_page_url(&headers);
+    let json_string = read_body(response).await?;
+    let value = parse_json(&json_string)?;
+    github.cache.insert(url, &headers, json_string, next_page.clone());
+    Ok((value, next_page))
+}
+
+/// Like [fetch_json], but for requests carrying their own credentials instead of those of the
+/// [Authenticator], e.g. the JSON web token of a GitHub App.
+pub(crate) async fn fetch_json_without_authenticator<Type: DeserializeOwned>(github: &GitHubClient, request: RequestBuilder) -> Result<Type, GitHubError> {
+    let (response, _permit) = send_request(github, request.headers(github.headers.clone())).await?;
+    let json_string = read_body(response).await?;
+    parse_json(&json_string)
+}
+
+async fn send_authorized_request(github: &GitHubClient, request: RequestBuilder) -> Result<(reqwest::Response, OwnedSemaphorePermit), GitHubError> {
+    let authorization = github.authenticator.get_authorization(github).await?;
+    let request = request
+        .headers(github.headers.clone())
+        .header(AUTHORIZATION, authorization);
+    send_request(github, request).await
+}
+
+/// Reads the body of the response, turning error statuses into the matching [GitHubError].
+async fn read_body(response: reqwest::Response) -> Result<String, GitHubError> {
+    let status_code = get_status_code(&response);
+    let is_rate_limited = get_rate_limit_delay(&response).is_some();
+    debug!("Retrieving {url} - Status code: {code}", url = response.url(), code = status_code);
+    let json_string = response.text().await?;
+    check_status(status_code, is_rate_limited, &json_string)?;
+    Ok(json_string)
+}
+
+fn parse_json<Type: DeserializeOwned>(json_string: &str) -> Result<Type, GitHubError> {
+    serde_json::from_str(json_string)
+        .map_err(|error| {
+            debug!("JSON data: {json}", json = json_string);
+            GitHubError::Decode(error)
+        })
+}
+
+/// Extracts the url marked as `rel="next"` from a `Link` header like
+/// `<https://api.github.com/...&page=2>; rel="next", <https://api.github.com/...&page=5>; rel="last"`.
+fn get_next_page_url(headers: &HeaderMap) -> Option<String> {
+    let link = headers.get(LINK)?.to_str().ok()?;
+    link.split(',')
+        .find_map(|entry| {
+            let mut parts = entry.split(';');
+            let url = parts.next()?.trim();
+            let is_next = parts.any(|parameter| parameter.trim() == "rel=\"next\"");
+            match is_next {
+                true => { Some(url.trim_start_matches('<').trim_end_matches('>').to_string()) }
+                false => { None }
+            }
+        })
+}
+
+/// Sends a request while respecting the rate limits of github: waits for the reset if the budget
+/// of the REST or GraphQL api the request is sent to is exhausted and repeats the request if it was rejected due to a primary or secondary rate limit.
+/// Network errors and server errors are retried with an exponential backoff.
+/// At most `max_concurrent_requests` requests are in flight at the same time. The permit of the
+/// request is returned along with the response and has to be held until its body has been read.
+async fn send_request(github: &GitHubClient, request: RequestBuilder) -> Result<(reqwest::Response, OwnedSemaphorePermit), GitHubError> {
+    let mut failed_attempts = 0;
+    let mut rate_limited_attempts = 0;
+    let is_graphql = request.try_clone()
+        .and_then(|request| request.build().ok())
+        .is_some_and(|request| request.url().as_str() == github.graphql_url);
+    let resource = match is_graphql {
+        true => { GRAPHQL_RESOURCE }
+        false => { CORE_RESOURCE }
+    };
+    loop {
+        wait_for_rate_limit(resource).await;
+        // Only requests with a streaming body can not be cloned, which are never sent to github
+        let attempt = request.try_clone().expect("Request can not be cloned");
+        let permit = github.request_permits.clone().acquire_owned().await
+            .expect("The request permits are never closed");
+        let response = match attempt.send().await {
+            Ok(response) => { response }
+            Err(error) if is_transient_error(&error) => {
+                drop(permit);
+                failed_attempts += 1;
+                match github.retry.get_delay(failed_attempts, None) {
+                    None => { return Err(GitHubError::Network(error)); }
+                    Some(delay) => {
+                        warn!("Request to github failed ({error}), retrying in {millis}ms", millis = delay.as_millis());
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                }
+            }
+            Err(error) => { return Err(GitHubError::Network(error)); }
+        };
+        update_rate_limit(response.headers());
+        if let Some(delay) = get_rate_limit_delay(&response) {
+            rate_limited_attempts += 1;
+            if rate_limited_attempts < MAX_RATE_LIMIT_ATTEMPTS {
+                drop(permit);
+                warn!("Rate limited by github on {url}, retrying in {seconds}s", url = response.url(), seconds = delay.as_secs());
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+        } else if is_transient_failure(&response) {
+            failed_attempts += 1;
+            if let Some(delay) = github.retry.get_delay(failed_attempts, get_retry_after(&response)) {
+                drop(permit);
+                warn!("Github answered {url} with {status}, retrying in {millis}ms", url = response.url(), status = response.status(), millis = delay.as_millis());
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+        }
+        return Ok((response, permit));
+    }
+}
+
+fn get_status_code(response: &reqwest::Response) -> u16 {
+    response.status().as_u16()
+}
+
+async fn list_commits_in_repository_since(github: &GitHubClient, full_repository_name: String, since: DateTime<Utc>) -> Result<Vec<Commit>, GitHubError> {
+    let mut params = HashMap::new();
+    params.insert("since", since.to_rfc3339_opts(SecondsFormat::Secs, true));
+    let url = format!("{api_url}/repos/{full_name}/commits", api_url = github.api_url, full_name = full_repository_name);
+    let result = match fetch_all_pages(github, url, &params).await {
+        // Github answers with 409 Conflict for repositories without any commits
+        Err(GitHubError::Client { status: 409, .. }) => { Ok(Vec::new()) }
+        result => { result }
+    };
+    let commits = observe("list_commits", result)?;
+    debug!("Retrieved {count} commits of {repo}", count = commits.len(), repo = full_repository_name);
+    Ok(commits)
+}
+
+async fn fetch_commit(github: &GitHubClient, full_repository_name: &str, commit_reference: &str) -> Result<CommitChangeDetails, GitHubError> {
+    let url = format!("{api_url}/repos/{full_name}/commits/{reference}", api_url = github.api_url, full_name = full_repository_name, reference = commit_reference);
+    debug!("Retrieving all details of commit {commit}", commit = commit_reference);
+    let result = fetch_json(github, github.client.get(url)).await;
+    let (commit_details, _) = observe("get_commit", result)?;
+    Ok(commit_details)
+}
+
+/// The headers sent with every request, the `Authorization` header is added by the [Authenticator].
+fn create_default_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("Accept", HeaderValue::from_static("application/vnd.github+json"));
+    headers.insert("User-Agent", HeaderValue::from_static("github-exporter-arm64-rs"));
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+    use serde_json::json;
+    use super::*;
+    use crate::fixture::{json_response, repository_response, start_mock_server};
+
+    fn client(api_url: String) -> GitHubClient {
+        let mut config = Config { api_url, token: String::from("test-token"), ..Config::default() };
+        // Retries of transient errors are not involved in waiting for a rate limit
+        config.retry.max_attempts = 1;
+        GitHubClient::from(&config).expect("Invalid client config")
+    }
+
+    #[tokio::test]
+    async fn waits_for_rate_limit_without_using_up_the_retries() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let api_url = start_mock_server(move |_, _| {
+            match counter.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => {
+                    let mut response = json_response(StatusCode::TOO_MANY_REQUESTS, json!({ "message": "API rate limit exceeded" }));
+                    response.headers_mut().insert("Retry-After", HeaderValue::from_static("0"));
+                    response
+                }
+                _ => { json_response(StatusCode::OK, json!([])) }
+            }
+        });
+        let repositories = list_repositories(&client(api_url), &Target::Organization(String::from("octo-org"))).await;
+        assert_eq!(repositories.expect("Rate limited request failed").len(), 0);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    /// The query and the `If-None-Match` and `If-Modified-Since` headers of a request.
+    type ReceivedRequest = (String, Option<String>, Option<String>);
+
+    #[tokio::test]
+    async fn reuses_cached_pages_if_github_answers_not_modified() {
+        const ETAG_VALUE: &str = "\"page-1\"";
+        const LAST_MODIFIED_VALUE: &str = "Sat, 20 May 2023 10:00:00 GMT";
+        let requests: Arc<Mutex<Vec<ReceivedRequest>>> = Arc::default();
+        let received = requests.clone();
+        let api_url = start_mock_server(move |base_url, request| {
+            let header = |name| request.headers().get(name).and_then(|value: &HeaderValue| value.to_str().ok()).map(String::from);
+            let page = request.uri().query().unwrap_or_default().to_string();
+            let is_conditional = header(IF_NONE_MATCH).is_some() || header(IF_MODIFIED_SINCE).is_some();
+            received.lock().unwrap().push((page.clone(), header(IF_NONE_MATCH), header(IF_MODIFIED_SINCE)));
+            if is_conditional {
+                return json_response(StatusCode::NOT_MODIFIED, json!(null));
+            }
+            match page.contains("page=2") {
+                false => {
+                    let mut response = json_response(StatusCode::OK, json!([repository_response(1, "octo-org", "exporter")]));
+                    let link = format!("<{base_url}/orgs/octo-org/repos?per_page=100&page=2>; rel=\"next\"");
+                    response.headers_mut().insert(LINK, link.parse().expect("Invalid link header"));
+                    response.headers_mut().insert(ETAG, HeaderValue::from_static(ETAG_VALUE));
+                    response
+                }
+                true => {
+                    let mut response = json_response(StatusCode::OK, json!([repository_response(2, "octo-org", "dashboard")]));
+                    response.headers_mut().insert(LAST_MODIFIED, HeaderValue::from_static(LAST_MODIFIED_VALUE));
+                    response
+                }
+            }
+        });
+        let github = client(api_url);
+        let target = Target::Organization(String::from("octo-org"));
+        let names = |repositories: Result<Vec<MinimalRepository>, GitHubError>| -> Vec<String> {
+            repositories.expect("Listing the repositories failed").into_iter().map(|repository| repository.name).collect()
+        };
+        let expected = vec![String::from("exporter"), String::from("dashboard")];
+        assert_eq!(names(list_repositories(&github, &target).await), expected);
+        // The cached bodies and their links to the next page are reused for the 304 responses
+        assert_eq!(names(list_repositories(&github, &target).await), expected);
+        // Used during the last sync, so the responses are kept
+        github.sync_finished();
+        assert_eq!(names(list_repositories(&github, &target).await), expected);
+        // Unused during the last sync, so the responses are dropped
+        github.sync_finished();
+        github.sync_finished();
+        assert_eq!(names(list_repositories(&github, &target).await), expected);
+
+        let first_page = (String::from("per_page=100"), None, None);
+        let second_page = (String::from("per_page=100&page=2"), None, None);
+        let conditional_first_page = (String::from("per_page=100"), Some(String::from(ETAG_VALUE)), None);
+        let conditional_second_page = (String::from("per_page=100&page=2"), None, Some(String::from(LAST_MODIFIED_VALUE)));
+        let expected_requests = vec![
+            first_page.clone(), second_page.clone(),
+            conditional_first_page.clone(), conditional_second_page.clone(),
+            conditional_first_page, conditional_second_page,
+            first_page, second_page,
+        ];
+        assert_eq!(*requests.lock().unwrap(), expected_requests);
+    }
+}
@@ -0,0 +1,432 @@
+use std::env;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::anyhow;
+use serde::Deserialize;
+use crate::auth::AppConfig;
+use crate::bots::BotConfig;
+use crate::filter::FilterConfig;
+use crate::retry::RetryConfig;
+
+/// The configuration of the exporter. It is read from an optional TOML or YAML file, after which
+/// every value may be overridden by its environment variable.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The github organizations whose repositories are exported (`ORG`, comma separated)
+    pub organizations: Vec<String>,
+    /// The github users whose own repositories are exported (`USERS`, comma separated)
+    pub users: Vec<String>,
+    /// The personal access token used for the github api (`TOKEN`)
+    pub token: String,
+    /// File containing the token, e.g. a mounted secret, re-read periodically and on SIGHUP (`TOKEN_FILE`)
+    pub token_file: Option<PathBuf>,
+    /// Authenticate as GitHub App instead of with a personal access token
+    pub app: Option<AppConfig>,
+    /// The base url of the github REST api, for GitHub Enterprise Server usually
+    /// `https://<host>/api/v3` (`GITHUB_API_URL`)
+    pub api_url: String,
+    /// The url of the github GraphQL api, derived from the api url if unset (`GITHUB_GRAPHQL_URL`)
+    pub graphql_url: Option<String>,
+    /// Which api the commits are fetched with, `rest` or `graphql` (`BACKEND`)
+    pub backend: Backend,
+    /// PEM file with additional CA certificates trusted for the api (`GITHUB_CA_BUNDLE`)
+    pub ca_bundle: Option<PathBuf>,
+    /// The port the metrics server listens on if the listen address does not contain one (`PORT`)
+    pub port: Option<u16>,
+    /// Comma separated addresses or hostnames the metrics server binds to (`LISTEN_ADDR`)
+    pub listen_addr: String,
+    /// Seconds between two syncs with github (`POLL_INTERVAL`)
+    pub poll_interval: u64,
+    /// Maximum number of requests sent to github at the same time (`MAX_CONCURRENT_REQUESTS`)
+    pub max_concurrent_requests: usize,
+    /// Directory the sync state is persisted in, nothing is persisted if unset (`DATA_DIR`)
+    pub data_dir: Option<PathBuf>,
+    /// Mailmap file merging author identities in the per-author metrics (`MAILMAP`)
+    pub mailmap: Option<PathBuf>,
+    /// Restricts which repositories of the organizations and users are exported
+    pub filters: FilterConfig,
+    /// How requests failing due to transient errors are retried
+    pub retry: RetryConfig,
+    /// Which commits are made by bots and how they are counted
+    pub bots: BotConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            organizations: Vec::new(),
+            users: Vec::new(),
+            token: String::new(),
+            token_file: None,
+            app: None,
+            api_url: String::from("https://api.github.com"),
+            graphql_url: None,
+            backend: Backend::default(),
+            ca_bundle: None,
+            port: None,
+            listen_addr: String::from("127.0.0.1"),
+            poll_interval: 60,
+            max_concurrent_requests: 8,
+            data_dir: None,
+            mailmap: None,
+            filters: FilterConfig::default(),
+            retry: RetryConfig::default(),
+            bots: BotConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration file (if any), applies the environment overrides and validates the result.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Config> {
+        let mut config = match path {
+            None => { Config::default() }
+            Some(path) => { Config::from_file(path)? }
+        };
+        config.apply_env_overrides(|name| env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Config> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read config file '{path}': {e}", path = path.display()))?;
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+        match extension {
+            "toml" => {
+                toml::from_str(&content)
+                    .map_err(|e| anyhow!("Invalid config file '{path}': {e}", path = path.display()))
+            }
+            "yaml" | "yml" => {
+                serde_yaml::from_str(&content)
+                    .map_err(|e| anyhow!("Invalid config file '{path}': {e}", path = path.display()))
+            }
+            _ => { Err(anyhow!("Unsupported config file '{path}', expected a .toml, .yaml or .yml file", path = path.display())) }
+        }
+    }
+
+    /// Overrides the values with the environment variables, which are looked up with `lookup`.
+    fn apply_env_overrides(&mut self, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        if let Some(organizations) = lookup("ORG") {
+            self.organizations = split_list(&organizations);
+        }
+        if let Some(users) = lookup("USERS") {
+            self.users = split_list(&users);
+        }
+        if let Some(token) = lookup("TOKEN") {
+            self.token = token;
+        }
+        if let Some(token_file) = lookup("TOKEN_FILE") {
+            self.token_file = Some(PathBuf::from(token_file));
+        }
+        if let Some(app_id) = parse_env_var(&lookup, "GITHUB_APP_ID")? {
+            self.app.get_or_insert_with(AppConfig::default).app_id = app_id;
+        }
+        if let Some(installation_id) = parse_env_var(&lookup, "GITHUB_APP_INSTALLATION_ID")? {
+            self.app.get_or_insert_with(AppConfig::default).installation_id = installation_id;
+        }
+        if let Some(private_key_path) = lookup("GITHUB_APP_PRIVATE_KEY_PATH") {
+            self.app.get_or_insert_with(AppConfig::default).private_key_path = PathBuf::from(private_key_path);
+        }
+        if let Some(api_url) = lookup("GITHUB_API_URL") {
+            self.api_url = api_url;
+        }
+        if let Some(graphql_url) = lookup("GITHUB_GRAPHQL_URL") {
+            self.graphql_url = Some(graphql_url);
+        }
+        if let Some(backend) = parse_env_var(&lookup, "BACKEND")? {
+            self.backend = backend;
+        }
+        if let Some(ca_bundle) = lookup("GITHUB_CA_BUNDLE") {
+            self.ca_bundle = Some(PathBuf::from(ca_bundle));
+        }
+        if let Some(port) = parse_env_var(&lookup, "PORT")? {
+            self.port = Some(port);
+        }
+        if let Some(listen_addr) = lookup("LISTEN_ADDR") {
+            self.listen_addr = listen_addr;
+        }
+        if let Some(poll_interval) = parse_env_var(&lookup, "POLL_INTERVAL")? {
+            self.poll_interval = poll_interval;
+        }
+        if let Some(max_concurrent_requests) = parse_env_var(&lookup, "MAX_CONCURRENT_REQUESTS")? {
+            self.max_concurrent_requests = max_concurrent_requests;
+        }
+        if let Some(mailmap) = lookup("MAILMAP") {
+            self.mailmap = Some(PathBuf::from(mailmap));
+        }
+        if let Some(data_dir) = lookup("DATA_DIR") {
+            self.data_dir = Some(PathBuf::from(data_dir));
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.targets().is_empty() {
+            return Err(anyhow!("No organization or user configured, set 'organizations'/'users' in the config file or the environment variables 'ORG'/'USERS'"));
+        }
+        if let Some(target) = self.targets().iter().find(|target| target.name().trim().is_empty()) {
+            return Err(anyhow!("Empty name configured for {target:?}"));
+        }
+        let has_token = !self.token.trim().is_empty();
+        if has_token && self.token_file.is_some() {
+            return Err(anyhow!("Both 'token' and 'token_file' are configured, only one of them may be used"));
+        }
+        if let Some(token_file) = &self.token_file {
+            if token_file.as_os_str().is_empty() {
+                return Err(anyhow!("Empty 'token_file' configured"));
+            }
+        }
+        let has_token = has_token || self.token_file.is_some();
+        match &self.app {
+            None if !has_token => {
+                return Err(anyhow!("No github-token configured, set 'token' or 'token_file' in the config file or the environment variable 'TOKEN' or 'TOKEN_FILE', or configure a GitHub App"));
+            }
+            None => {}
+            Some(_) if has_token => {
+                return Err(anyhow!("Both a github-token and a GitHub App are configured, only one of them may be used"));
+            }
+            Some(app) => {
+                if app.app_id == 0 {
+                    return Err(anyhow!("No GitHub App id configured, set 'app.app_id' in the config file or the environment variable 'GITHUB_APP_ID'"));
+                }
+                if app.installation_id == 0 {
+                    return Err(anyhow!("No GitHub App installation id configured, set 'app.installation_id' in the config file or the environment variable 'GITHUB_APP_INSTALLATION_ID'"));
+                }
+                if app.private_key_path.as_os_str().is_empty() {
+                    return Err(anyhow!("No GitHub App private key configured, set 'app.private_key_path' in the config file or the environment variable 'GITHUB_APP_PRIVATE_KEY_PATH'"));
+                }
+            }
+        }
+        if reqwest::Url::parse(&self.api_url).map(|url| url.cannot_be_a_base()).unwrap_or(true) {
+            return Err(anyhow!("Invalid github api url '{url}'", url = self.api_url));
+        }
+        if reqwest::Url::parse(&self.graphql_url()).map(|url| url.cannot_be_a_base()).unwrap_or(true) {
+            return Err(anyhow!("Invalid github GraphQL url '{url}'", url = self.graphql_url()));
+        }
+        if self.listen_addr.trim().is_empty() {
+            return Err(anyhow!("The listen address must not be empty"));
+        }
+        if let Some(entry) = split_list(&self.listen_addr).iter().find(|entry| !has_listen_port(entry)) {
+            if self.port.is_none() {
+                return Err(anyhow!("No port configured for the listen address '{entry}', set 'port' in the config file or the environment variable 'PORT'"));
+            }
+        }
+        if self.poll_interval == 0 {
+            return Err(anyhow!("The poll interval must be at least one second"));
+        }
+        if self.max_concurrent_requests == 0 {
+            return Err(anyhow!("The maximum number of concurrent requests must be at least one"));
+        }
+        if self.retry.max_attempts == 0 {
+            return Err(anyhow!("The maximum number of attempts per request must be at least one"));
+        }
+        if self.retry.base_delay_ms > self.retry.max_delay_ms {
+            return Err(anyhow!("The base delay between retries must not exceed the maximum delay"));
+        }
+        Ok(())
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval)
+    }
+
+    /// The GraphQL api lives at `/graphql` next to the REST api on github.com, and at
+    /// `/api/graphql` instead of `/api/v3` on GitHub Enterprise Server.
+    pub fn graphql_url(&self) -> String {
+        if let Some(graphql_url) = &self.graphql_url {
+            return graphql_url.trim_end_matches('/').to_string();
+        }
+        let api_url = self.api_url.trim_end_matches('/');
+        match api_url.strip_suffix("/v3") {
+            Some(base) => { format!("{base}/graphql") }
+            None => { format!("{api_url}/graphql") }
+        }
+    }
+
+    /// All organizations and users whose repositories are exported.
+    pub fn targets(&self) -> Vec<Target> {
+        let organizations = self.organizations.iter().cloned().map(Target::Organization);
+        let users = self.users.iter().cloned().map(Target::User);
+        organizations.chain(users).collect()
+    }
+}
+
+/// The api used to fetch the commits of the repositories. The REST api needs an additional request
+/// per commit for its statistics, whereas the GraphQL api returns them along with the history.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Rest,
+    GraphQl,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "rest" => { Ok(Backend::Rest) }
+            "graphql" => { Ok(Backend::GraphQl) }
+            _ => { Err(anyhow!("Unknown backend '{value}', expected 'rest' or 'graphql'")) }
+        }
+    }
+}
+
+/// An owner of repositories on github.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Organization(String),
+    User(String),
+}
+
+impl Target {
+    pub fn name(&self) -> &str {
+        match self {
+            Target::Organization(name) => { name }
+            Target::User(name) => { name }
+        }
+    }
+}
+
+/// Reads the path of the config file from the `--config <path>` or `--config=<path>` argument.
+pub fn parse_config_path(mut args: impl Iterator<Item = String>) -> anyhow::Result<Option<PathBuf>> {
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return match args.next() {
+                None => { Err(anyhow!("Missing path after '--config'")) }
+                Some(path) => { Ok(Some(PathBuf::from(path))) }
+            };
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Ok(Some(PathBuf::from(path)));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether an entry of the listen addresses like `[::1]:9090` or `localhost:9090` contains a port.
+fn has_listen_port(entry: &str) -> bool {
+    if entry.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    if entry.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_ok() {
+        return false;
+    }
+    entry.contains(':')
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn parse_env_var<Type: FromStr>(lookup: impl Fn(&str) -> Option<String>, name: &str) -> anyhow::Result<Option<Type>> {
+    match lookup(name) {
+        None => { Ok(None) }
+        Some(value) => {
+            value.trim().parse::<Type>()
+                .map(Some)
+                .map_err(|_| anyhow!("Invalid value '{value}' for environment variable '{name}'"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    fn valid_config() -> Config {
+        Config {
+            organizations: vec![String::from("octo-org")],
+            token: String::from("ghp_token"),
+            port: Some(9090),
+            ..Config::default()
+        }
+    }
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn applies_the_environment_overrides() {
+        let environment = HashMap::from([
+            ("ORG", "octo-org, other-org,"),
+            ("PORT", " 9100 "),
+            ("BACKEND", "GraphQL"),
+            ("TOKEN_FILE", "/run/secrets/token"),
+        ]);
+        let lookup = |name: &str| environment.get(name).map(|value| value.to_string());
+        let mut config = Config { organizations: vec![String::from("from-file")], ..Config::default() };
+        config.apply_env_overrides(lookup).expect("Invalid environment variables");
+        assert_eq!(config.organizations, vec![String::from("octo-org"), String::from("other-org")]);
+        assert_eq!(config.port, Some(9100));
+        assert_eq!(config.backend, Backend::GraphQl);
+        assert_eq!(config.token_file, Some(PathBuf::from("/run/secrets/token")));
+        assert_eq!(config.listen_addr, "127.0.0.1");
+        let invalid = |name: &str| (name == "POLL_INTERVAL").then(|| String::from("soon"));
+        assert!(Config::default().apply_env_overrides(invalid).is_err());
+    }
+
+    #[test]
+    fn accepts_a_complete_config() {
+        assert!(valid_config().validate().is_ok());
+        let app = AppConfig { app_id: 1, installation_id: 2, private_key_path: PathBuf::from("key.pem") };
+        assert!(Config { token: String::new(), app: Some(app), ..valid_config() }.validate().is_ok());
+        // Every listen address has a port of its own
+        assert!(Config { port: None, listen_addr: String::from("[::1]:9100, localhost:9100"), ..valid_config() }.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_incomplete_or_conflicting_configs() {
+        let invalid = [
+            Config { organizations: Vec::new(), ..valid_config() },
+            Config { users: vec![String::from(" ")], ..valid_config() },
+            Config { token: String::new(), ..valid_config() },
+            Config { token_file: Some(PathBuf::from("token")), ..valid_config() },
+            Config { app: Some(AppConfig { app_id: 1, installation_id: 2, private_key_path: PathBuf::from("key.pem") }), ..valid_config() },
+            Config { token: String::new(), app: Some(AppConfig { app_id: 1, ..AppConfig::default() }), ..valid_config() },
+            Config { api_url: String::from("not a url"), ..valid_config() },
+            Config { port: None, ..valid_config() },
+            Config { port: None, listen_addr: String::from("127.0.0.1:9100,::1"), ..valid_config() },
+            Config { listen_addr: String::from(" "), ..valid_config() },
+            Config { poll_interval: 0, ..valid_config() },
+            Config { max_concurrent_requests: 0, ..valid_config() },
+            Config { retry: RetryConfig { max_attempts: 0, ..RetryConfig::default() }, ..valid_config() },
+            Config { retry: RetryConfig { base_delay_ms: 2000, max_delay_ms: 1000, ..RetryConfig::default() }, ..valid_config() },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{config:?} should be invalid");
+        }
+    }
+
+    #[test]
+    fn derives_the_graphql_url_from_the_api_url() {
+        assert_eq!(valid_config().graphql_url(), "https://api.github.com/graphql");
+        let enterprise = Config { api_url: String::from("https://github.example.com/api/v3/"), ..valid_config() };
+        assert_eq!(enterprise.graphql_url(), "https://github.example.com/api/graphql");
+        let proxy = Config { api_url: String::from("http://localhost:8080/github"), ..valid_config() };
+        assert_eq!(proxy.graphql_url(), "http://localhost:8080/github/graphql");
+        let configured = Config { graphql_url: Some(String::from("https://graphql.example.com/")), ..enterprise };
+        assert_eq!(configured.graphql_url(), "https://graphql.example.com");
+    }
+
+    #[test]
+    fn parses_the_config_path_argument() {
+        assert_eq!(parse_config_path(args(&[])).unwrap(), None);
+        assert_eq!(parse_config_path(args(&["--verbose"])).unwrap(), None);
+        assert_eq!(parse_config_path(args(&["--config", "exporter.toml"])).unwrap(), Some(PathBuf::from("exporter.toml")));
+        assert_eq!(parse_config_path(args(&["--config=exporter.yaml"])).unwrap(), Some(PathBuf::from("exporter.yaml")));
+        assert!(parse_config_path(args(&["--config"])).is_err());
+    }
+}
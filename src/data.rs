@@ -1,6 +1,12 @@
 use std::hash::{Hash, Hasher};
+use std::sync::{Arc, RwLock};
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
 
+/// The repositories exported by the most recent sync of the background polling loop, including
+/// those whose commits could not be fetched, `None` until the first successful sync.
+pub type Snapshot = Arc<RwLock<Option<Vec<MinimalRepository>>>>;
+
 #[derive(Debug, Deserialize, Clone, Hash, PartialEq, Eq)]
 pub struct SimpleUser {
     pub name: Option<String>,
@@ -247,6 +253,14 @@ pub struct Commit {
     pub parents: Vec<Parent>,
 }
 
+impl Commit {
+    /// The date the commit was committed at, which is what the `since` parameter of github filters on.
+    pub fn committed_at(&self) -> Option<DateTime<Utc>> {
+        let user = self.commit.committer.as_ref().or(self.commit.author.as_ref())?;
+        DateTime::parse_from_rfc3339(&user.date).ok().map(|date| date.with_timezone(&Utc))
+    }
+}
+
 #[derive(Debug, Deserialize, Clone, Hash, PartialEq, Eq)]
 pub struct CommitDetails {
     pub url: String,
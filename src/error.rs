@@ -0,0 +1,72 @@
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Why a request to github did not yield the expected data.
+#[derive(Debug, Error)]
+pub enum GitHubError {
+    #[error("unauthorized ({status}): {message} - check that the token is valid and has access")]
+    Unauthorized { status: u16, message: String },
+    #[error("not found: {message}")]
+    NotFound { message: String },
+    #[error("rate limited ({status}): {message}")]
+    RateLimited { status: u16, message: String },
+    #[error("server error ({status}): {message}")]
+    Server { status: u16, message: String },
+    #[error("unexpected response ({status}): {message}")]
+    Client { status: u16, message: String },
+    #[error("failed to decode response: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("request failed: {0}")]
+    Network(#[from] reqwest::Error),
+    #[error("authentication failed: {0}")]
+    Authentication(String),
+    #[error("graphql query failed: {0}")]
+    GraphQl(String),
+}
+
+/// The body github sends along with an error status.
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    message: String,
+}
+
+impl GitHubError {
+    /// The value of the `kind` label of the api error metric.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GitHubError::Unauthorized { .. } => { "unauthorized" }
+            GitHubError::NotFound { .. } => { "not_found" }
+            GitHubError::RateLimited { .. } => { "rate_limited" }
+            GitHubError::Server { .. } => { "server" }
+            GitHubError::Client { .. } => { "client" }
+            GitHubError::Decode(_) => { "decode" }
+            GitHubError::Network(_) => { "network" }
+            GitHubError::Authentication(_) => { "authentication" }
+            GitHubError::GraphQl(_) => { "graphql" }
+        }
+    }
+
+    /// Classifies a response that was not successful. `is_rate_limited` tells whether the
+    /// response carried rate limit information, as github also uses 403 for exhausted limits.
+    pub fn from_status(status: u16, is_rate_limited: bool, body: &str) -> GitHubError {
+        let message = serde_json::from_str::<ErrorBody>(body)
+            .map(|body| body.message)
+            .unwrap_or_else(|_| body.chars().take(200).collect());
+        match status {
+            403 | 429 if is_rate_limited => { GitHubError::RateLimited { status, message } }
+            401 | 403 => { GitHubError::Unauthorized { status, message } }
+            404 => { GitHubError::NotFound { message } }
+            429 => { GitHubError::RateLimited { status, message } }
+            500..=599 => { GitHubError::Server { status, message } }
+            _ => { GitHubError::Client { status, message } }
+        }
+    }
+}
+
+/// Turns error statuses into the matching [GitHubError].
+pub fn check_status(status: u16, is_rate_limited: bool, body: &str) -> Result<(), GitHubError> {
+    if !(200..300).contains(&status) {
+        return Err(GitHubError::from_status(status, is_rate_limited, body));
+    }
+    Ok(())
+}
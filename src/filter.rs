@@ -0,0 +1,231 @@
+use anyhow::anyhow;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use regex::RegexSet;
+use serde::Deserialize;
+use crate::data::MinimalRepository;
+
+/// Which repositories of the configured owners are exported. Empty lists and unset flags do not
+/// restrict anything, so the default configuration exports every repository.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    /// Globs the repository name or full name (`owner/name`) has to match
+    pub include: Vec<String>,
+    /// Globs of repository names or full names that are never exported
+    pub exclude: Vec<String>,
+    /// Regular expressions a repository name has to match
+    pub include_regex: Vec<String>,
+    /// Regular expressions of repository names that are never exported
+    pub exclude_regex: Vec<String>,
+    /// The repository needs at least one of these topics
+    pub topics: Vec<String>,
+    /// Repositories with any of these topics are never exported
+    pub exclude_topics: Vec<String>,
+    /// Allowed visibilities, e.g. `public`, `private` or `internal`
+    pub visibility: Vec<String>,
+    /// Allowed primary languages as reported by github
+    pub languages: Vec<String>,
+    /// Only export archived (`true`) or only active (`false`) repositories
+    pub archived: Option<bool>,
+    /// Only export forks (`true`) or only non-forks (`false`)
+    pub fork: Option<bool>,
+    /// Only export templates (`true`) or only non-templates (`false`)
+    pub is_template: Option<bool>,
+}
+
+/// The compiled form of a [FilterConfig].
+#[derive(Debug, Clone)]
+pub struct RepositoryFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    include_regex: Option<RegexSet>,
+    exclude_regex: RegexSet,
+    config: FilterConfig,
+}
+
+impl RepositoryFilter {
+    pub fn from(config: &FilterConfig) -> anyhow::Result<RepositoryFilter> {
+        let include = match config.include.is_empty() {
+            true => { None }
+            false => { Some(build_glob_set(&config.include)?) }
+        };
+        let include_regex = match config.include_regex.is_empty() {
+            true => { None }
+            false => { Some(build_regex_set(&config.include_regex)?) }
+        };
+        Ok(RepositoryFilter {
+            include,
+            exclude: build_glob_set(&config.exclude)?,
+            include_regex,
+            exclude_regex: build_regex_set(&config.exclude_regex)?,
+            config: config.clone(),
+        })
+    }
+
+    pub fn matches(&self, repository: &MinimalRepository) -> bool {
+        let names = [repository.name.as_str(), repository.full_name.as_str()];
+        if let Some(include) = &self.include {
+            if !names.iter().any(|name| include.is_match(name)) {
+                return false;
+            }
+        }
+        if names.iter().any(|name| self.exclude.is_match(name)) {
+            return false;
+        }
+        if let Some(include_regex) = &self.include_regex {
+            if !include_regex.is_match(&repository.name) {
+                return false;
+            }
+        }
+        if self.exclude_regex.is_match(&repository.name) {
+            return false;
+        }
+        if !self.config.topics.is_empty() && !repository.topics.iter().any(|topic| self.config.topics.contains(topic)) {
+            return false;
+        }
+        if repository.topics.iter().any(|topic| self.config.exclude_topics.contains(topic)) {
+            return false;
+        }
+        if !self.config.visibility.is_empty() && !self.config.visibility.iter().any(|visibility| visibility.eq_ignore_ascii_case(get_visibility(repository))) {
+            return false;
+        }
+        if !self.config.languages.is_empty() {
+            let is_allowed_language = repository.language.as_ref()
+                .map(|language| self.config.languages.iter().any(|allowed| allowed.eq_ignore_ascii_case(language)))
+                .unwrap_or(false);
+            if !is_allowed_language {
+                return false;
+            }
+        }
+        matches_flag(self.config.archived, repository.archived.unwrap_or(false))
+            && matches_flag(self.config.fork, repository.fork)
+            && matches_flag(self.config.is_template, repository.is_template)
+    }
+}
+
+/// Older github versions only report whether a repository is private.
+fn get_visibility(repository: &MinimalRepository) -> &str {
+    match &repository.visibility {
+        Some(visibility) => { visibility.as_str() }
+        None if repository.private => { "private" }
+        None => { "public" }
+    }
+}
+
+fn matches_flag(expected: Option<bool>, actual: bool) -> bool {
+    expected.map(|expected| expected == actual).unwrap_or(true)
+}
+
+/// `*` does not match the `/` of a full name, so that a glob of names never matches every repository of an owner.
+fn build_glob_set(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern).literal_separator(true).build().map_err(|e| anyhow!("Invalid repository glob '{pattern}': {e}"))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+fn build_regex_set(patterns: &[String]) -> anyhow::Result<RegexSet> {
+    RegexSet::new(patterns).map_err(|e| anyhow!("Invalid repository regex: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::repository;
+
+    fn matches(config: FilterConfig, repository: &MinimalRepository) -> bool {
+        RepositoryFilter::from(&config).expect("Invalid filter").matches(repository)
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn unset_filters_match_everything() {
+        let mut archived_fork = repository(1, "octo-org", "legacy");
+        archived_fork.archived = Some(true);
+        archived_fork.fork = true;
+        archived_fork.private = true;
+        archived_fork.visibility = None;
+        archived_fork.language = None;
+        archived_fork.topics = Vec::new();
+        assert!(matches(FilterConfig::default(), &repository(1, "octo-org", "exporter")));
+        assert!(matches(FilterConfig::default(), &archived_fork));
+    }
+
+    #[test]
+    fn globs_match_the_name_or_the_full_name() {
+        let exporter = repository(1, "octo-org", "exporter");
+        assert!(matches(FilterConfig { include: strings(&["export*"]), ..FilterConfig::default() }, &exporter));
+        assert!(matches(FilterConfig { include: strings(&["octo-org/*"]), ..FilterConfig::default() }, &exporter));
+        assert!(!matches(FilterConfig { include: strings(&["octocat/*", "dash*"]), ..FilterConfig::default() }, &exporter));
+        // A glob of names does not match the full name of every repository of a similarly named owner
+        assert!(!matches(FilterConfig { include: strings(&["octo*"]), ..FilterConfig::default() }, &exporter));
+        assert!(matches(FilterConfig { exclude: strings(&["octo*"]), ..FilterConfig::default() }, &exporter));
+        assert!(!matches(FilterConfig { exclude: strings(&["*porter"]), ..FilterConfig::default() }, &exporter));
+        assert!(!matches(FilterConfig { exclude: strings(&["octo-org/exporter"]), ..FilterConfig::default() }, &exporter));
+        // Exclusions win over inclusions
+        assert!(!matches(FilterConfig { include: strings(&["*"]), exclude: strings(&["exporter"]), ..FilterConfig::default() }, &exporter));
+        assert!(RepositoryFilter::from(&FilterConfig { include: strings(&["[invalid"]), ..FilterConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn regexes_match_the_name() {
+        let exporter = repository(1, "octo-org", "exporter");
+        assert!(matches(FilterConfig { include_regex: strings(&["^exp"]), ..FilterConfig::default() }, &exporter));
+        assert!(!matches(FilterConfig { include_regex: strings(&["^octo-org/"]), ..FilterConfig::default() }, &exporter));
+        assert!(!matches(FilterConfig { exclude_regex: strings(&["port"]), ..FilterConfig::default() }, &exporter));
+        assert!(RepositoryFilter::from(&FilterConfig { exclude_regex: strings(&["("]), ..FilterConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn topics_have_to_be_present_and_excluded_topics_absent() {
+        // The fixture has the topics "metrics" and "prometheus"
+        let exporter = repository(1, "octo-org", "exporter");
+        assert!(matches(FilterConfig { topics: strings(&["grafana", "metrics"]), ..FilterConfig::default() }, &exporter));
+        assert!(!matches(FilterConfig { topics: strings(&["grafana"]), ..FilterConfig::default() }, &exporter));
+        assert!(!matches(FilterConfig { exclude_topics: strings(&["prometheus"]), ..FilterConfig::default() }, &exporter));
+        assert!(matches(FilterConfig { exclude_topics: strings(&["deprecated"]), ..FilterConfig::default() }, &exporter));
+    }
+
+    #[test]
+    fn visibility_falls_back_to_the_private_flag() {
+        let mut repository = repository(1, "octo-org", "exporter");
+        let internal_only = FilterConfig { visibility: strings(&["Internal"]), ..FilterConfig::default() };
+        let private_only = FilterConfig { visibility: strings(&["private"]), ..FilterConfig::default() };
+        assert!(!matches(internal_only.clone(), &repository));
+        repository.visibility = Some(String::from("internal"));
+        assert!(matches(internal_only, &repository));
+        repository.visibility = None;
+        repository.private = true;
+        assert!(matches(private_only, &repository));
+    }
+
+    #[test]
+    fn languages_are_compared_case_insensitively() {
+        let mut repository = repository(1, "octo-org", "exporter");
+        assert!(matches(FilterConfig { languages: strings(&["rust"]), ..FilterConfig::default() }, &repository));
+        assert!(!matches(FilterConfig { languages: strings(&["Go"]), ..FilterConfig::default() }, &repository));
+        repository.language = None;
+        assert!(!matches(FilterConfig { languages: strings(&["rust"]), ..FilterConfig::default() }, &repository));
+    }
+
+    #[test]
+    fn flags_select_archived_forks_and_templates() {
+        let mut repository = repository(1, "octo-org", "exporter");
+        assert!(matches(FilterConfig { archived: Some(false), fork: Some(false), is_template: Some(false), ..FilterConfig::default() }, &repository));
+        assert!(!matches(FilterConfig { archived: Some(true), ..FilterConfig::default() }, &repository));
+        assert!(!matches(FilterConfig { fork: Some(true), ..FilterConfig::default() }, &repository));
+        assert!(!matches(FilterConfig { is_template: Some(true), ..FilterConfig::default() }, &repository));
+        // Older github versions do not report whether a repository is archived
+        repository.archived = None;
+        assert!(matches(FilterConfig { archived: Some(false), ..FilterConfig::default() }, &repository));
+        repository.archived = Some(true);
+        repository.fork = true;
+        repository.is_template = true;
+        assert!(matches(FilterConfig { archived: Some(true), fork: Some(true), is_template: Some(true), ..FilterConfig::default() }, &repository));
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use crate::config::Target;
+use crate::data::*;
+use crate::error::GitHubError;
+use crate::source::GitHubSource;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+pub use common::{json_response, repository as repository_response, start_mock_server};
+
+/// Serves fixed repositories and commits from memory instead of querying github.
+#[derive(Debug, Default)]
+pub struct FixtureSource {
+    repositories: Vec<MinimalRepository>,
+    /// Keyed by the full name (`owner/name`) of the repository
+    commits: HashMap<String, Vec<FullCommitData>>,
+    failing_repositories: Vec<String>,
+    requested_commits: AtomicUsize,
+}
+
+impl FixtureSource {
+    pub fn with_repository(mut self, repository: MinimalRepository) -> FixtureSource {
+        self.repositories.push(repository);
+        self
+    }
+
+    pub fn with_commit(mut self, full_repository_name: &str, commit: FullCommitData) -> FixtureSource {
+        self.commits.entry(full_repository_name.to_string()).or_default().push(commit);
+        self
+    }
+
+    /// Listing the commits of the repository fails like a github outage would.
+    pub fn with_failing_repository(mut self, full_repository_name: &str) -> FixtureSource {
+        self.failing_repositories.push(full_repository_name.to_string());
+        self
+    }
+
+    /// How often the changes of a single commit have been requested.
+    pub fn requested_commits(&self) -> usize {
+        self.requested_commits.load(Ordering::SeqCst)
+    }
+
+    fn find_commit(&self, full_repository_name: &str, sha: &str) -> Option<&FullCommitData> {
+        self.commits.get(full_repository_name)?.iter().find(|commit| commit.commit.sha == sha)
+    }
+}
+
+#[async_trait]
+impl GitHubSource for FixtureSource {
+    async fn list_repositories(&self, target: &Target) -> Result<Vec<MinimalRepository>, GitHubError> {
+        Ok(self.repositories.iter()
+            .filter(|repository| repository.owner.login == target.name())
+            .cloned()
+            .collect())
+    }
+
+    /// Like github, `since` is inclusive.
+    async fn list_commits(&self, full_repository_name: &str, since: DateTime<Utc>) -> Result<Vec<Commit>, GitHubError> {
+        if self.failing_repositories.iter().any(|failing| failing == full_repository_name) {
+            return Err(GitHubError::Server { status: 502, message: String::from("Server Error") });
+        }
+        Ok(self.commits.get(full_repository_name).into_iter()
+            .flatten()
+            .filter(|commit| commit.commit.committed_at().map(|committed_at| committed_at >= since).unwrap_or(true))
+            .map(|commit| commit.commit.clone())
+            .collect())
+    }
+
+    async fn get_commit(&self, full_repository_name: &str, sha: &str) -> Result<CommitChangeDetails, GitHubError> {
+        self.requested_commits.fetch_add(1, Ordering::SeqCst);
+        match self.find_commit(full_repository_name, sha) {
+            Some(commit) => { Ok(commit.changes.clone()) }
+            None => { Err(GitHubError::NotFound { message: String::from("No commit found for SHA") }) }
+        }
+    }
+
+    fn max_concurrent_requests(&self) -> usize {
+        2
+    }
+}
+
+/// A repository of the owner, based on a response of the github repository list.
+pub fn repository(id: i32, owner: &str, name: &str) -> MinimalRepository {
+    serde_json::from_value(common::repository(id, owner, name)).expect("Invalid repository fixture")
+}
+
+/// A commit committed at `date` (RFC 3339), based on a response of the github commit endpoints.
+pub fn commit(sha: &str, date: &str, additions: i32, deletions: i32) -> FullCommitData {
+    let commit: Commit = serde_json::from_value(common::commit(sha, date)).expect("Invalid commit fixture");
+    let changes: CommitChangeDetails = serde_json::from_value(common::commit_changes(sha, additions, deletions)).expect("Invalid commit changes fixture");
+    FullCommitData::from(commit, changes)
+}
@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+use anyhow::anyhow;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{stream, StreamExt};
+use log::debug;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use crate::data::*;
+use crate::error::GitHubError;
+use crate::state::State;
+use crate::client::{fetch_json, observe, GitHubClient};
+
+/// Number of repositories whose history is queried with a single request.
+const REPOSITORY_BATCH_SIZE: usize = 5;
+/// Commits per repository and request. Smaller than the REST page size, as github regularly
+/// times out computing the additions and deletions of larger pages.
+const COMMIT_PAGE_SIZE: u8 = 50;
+
+const HISTORY_FRAGMENT: &str = "
+fragment History on CommitHistoryConnection {
+  nodes {
+    id
+    oid
+    url
+    message
+    additions
+    deletions
+    author { ...Actor }
+    committer { ...Actor }
+    tree { oid }
+    parents(first: 10) { nodes { oid url } }
+    comments { totalCount }
+    signature { isValid state }
+  }
+  pageInfo { hasNextPage endCursor }
+}
+
+fragment Actor on GitActor {
+  name
+  email
+  date
+  user { id databaseId login avatarUrl url }
+}";
+
+#[derive(Debug, Deserialize)]
+struct GraphQlResponse {
+    data: Option<HashMap<String, Option<RepositoryNode>>>,
+    #[serde(default)]
+    errors: Vec<GraphQlError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphQlError {
+    message: String,
+    path: Option<Vec<Value>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RepositoryNode {
+    /// Empty repositories have no default branch
+    default_branch_ref: Option<BranchNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BranchNode {
+    target: Option<TargetNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TargetNode {
+    history: Option<HistoryNode>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryNode {
+    nodes: Vec<CommitNode>,
+    page_info: PageInfo,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageInfo {
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitNode {
+    id: String,
+    oid: String,
+    url: String,
+    message: String,
+    additions: i32,
+    deletions: i32,
+    author: Option<GitActorNode>,
+    committer: Option<GitActorNode>,
+    tree: ObjectNode,
+    parents: ParentsNode,
+    comments: CountNode,
+    signature: Option<SignatureNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitActorNode {
+    name: Option<String>,
+    email: Option<String>,
+    date: Option<String>,
+    user: Option<UserNode>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UserNode {
+    id: String,
+    database_id: Option<i32>,
+    login: String,
+    avatar_url: String,
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ObjectNode {
+    oid: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ParentsNode {
+    nodes: Vec<ParentNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ParentNode {
+    oid: String,
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CountNode {
+    total_count: i32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignatureNode {
+    is_valid: bool,
+    state: String,
+}
+
+/// A repository whose history has not been fetched completely yet.
+struct PendingRepository {
+    repository: MinimalRepository,
+    since: DateTime<Utc>,
+    cursor: Option<String>,
+    commits: Vec<FullCommitData>,
+}
+
+/// Fetches the commits of the repositories newer than their watermarks, including the number of
+/// added and deleted lines, with one request per [REPOSITORY_BATCH_SIZE] repositories and page.
+/// The changed files are not available via GraphQL, so the `files` of every commit stay empty.
+pub async fn get_repositories_and_commits_since(github: &GitHubClient, repositories: Vec<MinimalRepository>, state: &State) -> Vec<(String, anyhow::Result<RepositoryAndCommits>)> {
+    let batches: Vec<Vec<MinimalRepository>> = repositories.chunks(REPOSITORY_BATCH_SIZE).map(<[MinimalRepository]>::to_vec).collect();
+    stream::iter(batches)
+        .map(|batch| get_batch_and_commits_since(github, batch, state))
+        .buffer_unordered(github.max_concurrent_requests)
+        .flat_map(stream::iter)
+        .collect()
+        .await
+}
+
+/// Queries the next page of every repository of the batch that has more commits, until the
+/// histories of all of them are complete. If a request fails, its repositories are queried one by
+/// one, so that only the repositories actually failing are reported as failed.
+async fn get_batch_and_commits_since(github: &GitHubClient, repositories: Vec<MinimalRepository>, state: &State) -> Vec<(String, anyhow::Result<RepositoryAndCommits>)> {
+    let pending: Vec<PendingRepository> = repositories.into_iter()
+        .map(|repository| PendingRepository {
+            since: state.get_watermark(&repository.full_name),
+            repository,
+            cursor: None,
+            commits: Vec::new(),
+        })
+        .collect();
+    let mut results = Vec::new();
+    let mut groups = vec![pending];
+    while let Some(pending) = groups.pop() {
+        if pending.is_empty() {
+            continue;
+        }
+        debug!("Fetching the history of {count} repositories via GraphQL...", count = pending.len());
+        let response = match observe("graphql_history", query_histories(github, &pending).await) {
+            Ok(response) => { response }
+            // A single repository, e.g. one timing out on github, must not fail the others of its batch
+            Err(error) if pending.len() > 1 => {
+                debug!("Querying the {count} repositories of the failed batch one by one: {error}", count = pending.len());
+                groups.extend(pending.into_iter().map(|repository| vec![repository]));
+                continue;
+            }
+            Err(error) => {
+                for repository in pending {
+                    results.push((repository.repository.full_name, Err(anyhow!("{error}"))));
+                }
+                continue;
+            }
+        };
+        let mut data = response.data.unwrap_or_default();
+        let mut still_pending = Vec::new();
+        for (index, mut repository) in pending.into_iter().enumerate() {
+            let alias = get_alias(index);
+            let node = match data.remove(&alias).flatten() {
+                Some(node) => { node }
+                None => {
+                    let message = get_error_message(&response.errors, &alias);
+                    results.push((repository.repository.full_name, Err(anyhow!("{}", GitHubError::GraphQl(message)))));
+                    continue;
+                }
+            };
+            let history = node.default_branch_ref
+                .and_then(|branch| branch.target)
+                .and_then(|target| target.history);
+            let history = match history {
+                Some(history) => { history }
+                None => {
+                    debug!("{repo} has no commits", repo = &repository.repository.full_name);
+                    results.push(finish(repository));
+                    continue;
+                }
+            };
+            let full_name = repository.repository.full_name.clone();
+            repository.commits.extend(history.nodes.into_iter()
+                .filter(|commit| !state.is_counted(&full_name, &commit.oid))
+                .map(|commit| to_full_commit_data(&github.api_url, &full_name, commit)));
+            match (history.page_info.has_next_page, history.page_info.end_cursor) {
+                (true, Some(cursor)) => {
+                    repository.cursor = Some(cursor);
+                    still_pending.push(repository);
+                }
+                _ => { results.push(finish(repository)); }
+            }
+        }
+        groups.push(still_pending);
+    }
+    results
+}
+
+async fn query_histories(github: &GitHubClient, pending: &[PendingRepository]) -> Result<GraphQlResponse, GitHubError> {
+    let (query, variables) = build_query(pending);
+    let request = github.client.post(&github.graphql_url)
+        .json(&json!({ "query": query, "variables": variables }));
+    let (response, _): (GraphQlResponse, _) = fetch_json(github, request).await?;
+    if response.data.is_none() {
+        let messages: Vec<&str> = response.errors.iter().map(|error| error.message.as_str()).collect();
+        return Err(GitHubError::GraphQl(messages.join("; ")));
+    }
+    Ok(response)
+}
+
+/// Every repository is queried under its own alias with its own variables, as the watermarks
+/// and cursors differ between the repositories.
+fn build_query(pending: &[PendingRepository]) -> (String, Map<String, Value>) {
+    let mut parameters = Vec::new();
+    let mut selections = Vec::new();
+    let mut variables = Map::new();
+    for (index, repository) in pending.iter().enumerate() {
+        parameters.push(format!("$owner{index}: String!, $name{index}: String!, $since{index}: GitTimestamp!, $cursor{index}: String"));
+        selections.push(format!(
+            "  {alias}: repository(owner: $owner{index}, name: $name{index}) {{ defaultBranchRef {{ target {{ ... on Commit {{ history(first: {COMMIT_PAGE_SIZE}, since: $since{index}, after: $cursor{index}) {{ ...History }} }} }} }} }}",
+            alias = get_alias(index)
+        ));
+        variables.insert(format!("owner{index}"), json!(repository.repository.owner.login));
+        variables.insert(format!("name{index}"), json!(repository.repository.name));
+        variables.insert(format!("since{index}"), json!(repository.since.to_rfc3339_opts(SecondsFormat::Secs, true)));
+        variables.insert(format!("cursor{index}"), json!(repository.cursor));
+    }
+    let query = format!("query({parameters}) {{\n{selections}\n}}\n{HISTORY_FRAGMENT}", parameters = parameters.join(", "), selections = selections.join("\n"));
+    (query, variables)
+}
+
+fn get_alias(index: usize) -> String {
+    format!("repository{index}")
+}
+
+/// The messages of the errors github reported for the repository queried under the alias.
+fn get_error_message(errors: &[GraphQlError], alias: &str) -> String {
+    let messages: Vec<&str> = errors.iter()
+        .filter(|error| error.path.as_ref().and_then(|path| path.first()).and_then(Value::as_str) == Some(alias))
+        .map(|error| error.message.as_str())
+        .collect();
+    match messages.is_empty() {
+        true => { String::from("The repository is missing in the response") }
+        false => { messages.join("; ") }
+    }
+}
+
+fn finish(repository: PendingRepository) -> (String, anyhow::Result<RepositoryAndCommits>) {
+    let full_name = repository.repository.full_name.clone();
+    (full_name, Ok(RepositoryAndCommits::from(repository.repository, repository.commits)))
+}
+
+/// Converts the commit into the structure the REST api returns, deriving the api urls github
+/// does not expose via GraphQL.
+fn to_full_commit_data(api_url: &str, full_repository_name: &str, node: CommitNode) -> FullCommitData {
+    let repository_url = format!("{api_url}/repos/{full_repository_name}");
+    let sha = node.oid;
+    let verification = match node.signature {
+        Some(signature) => { Verification { verified: signature.is_valid, reason: signature.state.to_lowercase(), payload: None, signature: None } }
+        None => { Verification { verified: false, reason: String::from("unsigned"), payload: None, signature: None } }
+    };
+    let details = CommitDetails {
+        url: format!("{repository_url}/git/commits/{sha}"),
+        author: node.author.as_ref().map(to_git_user),
+        committer: node.committer.as_ref().map(to_git_user),
+        message: node.message,
+        comment_count: node.comments.total_count,
+        tree: Tree {
+            url: format!("{repository_url}/git/trees/{tree}", tree = node.tree.oid),
+            sha: node.tree.oid,
+        },
+        verification,
+    };
+    let commit = Commit {
+        url: format!("{repository_url}/commits/{sha}"),
+        node_id: node.id,
+        html_url: node.url,
+        comments_url: format!("{repository_url}/commits/{sha}/comments"),
+        commit: details,
+        author: node.author.and_then(|author| author.user).map(|user| to_simple_user(api_url, user)),
+        committer: node.committer.and_then(|committer| committer.user).map(|user| to_simple_user(api_url, user)),
+        parents: node.parents.nodes.into_iter()
+            .map(|parent| Parent {
+                url: format!("{repository_url}/commits/{parent}", parent = parent.oid),
+                sha: parent.oid,
+                html_url: parent.url,
+            })
+            .collect(),
+        sha,
+    };
+    let changes = CommitChangeDetails {
+        stats: CommitStats {
+            additions: node.additions,
+            deletions: node.deletions,
+            total: node.additions + node.deletions,
+        },
+        files: Vec::new(),
+    };
+    FullCommitData::from(commit, changes)
+}
+
+fn to_git_user(actor: &GitActorNode) -> GitUser {
+    GitUser {
+        name: actor.name.clone().unwrap_or_default(),
+        email: actor.email.clone().unwrap_or_default(),
+        date: actor.date.clone().unwrap_or_default(),
+    }
+}
+
+/// Only users are linked to git actors in GraphQL, bots and mannequins are not. Commits of bots
+/// therefore have no `author` account, unlike via REST, and are only recognizable by their git author.
+fn to_simple_user(api_url: &str, user: UserNode) -> SimpleUser {
+    let user_url = format!("{api_url}/users/{login}", login = user.login);
+    SimpleUser {
+        name: None,
+        email: None,
+        id: user.database_id.unwrap_or_default(),
+        node_id: user.id,
+        avatar_url: user.avatar_url,
+        gravatar_id: Some(String::new()),
+        html_url: user.url,
+        followers_url: format!("{user_url}/followers"),
+        following_url: format!("{user_url}/following{{/other_user}}"),
+        gists_url: format!("{user_url}/gists{{/gist_id}}"),
+        starred_url: format!("{user_url}/starred{{/owner}}{{/repo}}"),
+        subscriptions_url: format!("{user_url}/subscriptions"),
+        organizations_url: format!("{user_url}/orgs"),
+        repos_url: format!("{user_url}/repos"),
+        events_url: format!("{user_url}/events{{/privacy}}"),
+        received_events_url: format!("{user_url}/received_events"),
+        user_type: String::from("User"),
+        site_admin: false,
+        starred_at: None,
+        login: user.login,
+        url: user_url,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use reqwest::StatusCode;
+    use super::*;
+    use crate::config::{Backend, Config};
+    use crate::fixture::{json_response, repository, start_mock_server};
+
+    fn client(base_url: &str) -> GitHubClient {
+        let mut config = Config {
+            api_url: base_url.to_string(),
+            graphql_url: Some(format!("{base_url}/graphql")),
+            backend: Backend::GraphQl,
+            token: String::from("test-token"),
+            ..Config::default()
+        };
+        config.retry.max_attempts = 1;
+        GitHubClient::from(&config).expect("Invalid client config")
+    }
+
+    fn commit_node(oid: &str) -> Value {
+        json!({
+            "id": format!("C_{oid}"),
+            "oid": oid,
+            "url": format!("https://github.com/octo-org/exporter/commit/{oid}"),
+            "message": "Fix the exporter",
+            "additions": 10,
+            "deletions": 2,
+            "author": {
+                "name": "Mona Octocat",
+                "email": "mona@github.com",
+                "date": "2023-05-20T10:00:00Z",
+                "user": { "id": "U_1", "databaseId": 583231, "login": "octocat", "avatarUrl": "https://github.com/images/octocat.gif", "url": "https://github.com/octocat" }
+            },
+            "committer": { "name": "GitHub", "email": "noreply@github.com", "date": "2023-05-20T10:00:00Z", "user": null },
+            "tree": { "oid": "t1" },
+            "parents": { "nodes": [{ "oid": "p1", "url": "https://github.com/octo-org/exporter/commit/p1" }] },
+            "comments": { "totalCount": 3 },
+            "signature": { "isValid": true, "state": "VALID" }
+        })
+    }
+
+    fn history(commits: Vec<Value>) -> Value {
+        json!({ "defaultBranchRef": { "target": { "history": { "nodes": commits, "pageInfo": { "hasNextPage": false, "endCursor": null } } } } })
+    }
+
+    /// The names of the repositories queried by a request, indexed like their aliases.
+    fn queried_names(body: &str) -> Vec<String> {
+        let body: Value = serde_json::from_str(body).expect("Invalid GraphQL request");
+        (0..).map_while(|index| body["variables"][format!("name{index}")].as_str().map(String::from)).collect()
+    }
+
+    #[test]
+    fn queries_every_repository_under_its_own_alias() {
+        let since = Utc.with_ymd_and_hms(2023, 5, 20, 10, 0, 0).single().expect("Invalid timestamp");
+        let pending: Vec<PendingRepository> = [(1, "exporter", None), (2, "dashboard", Some(String::from("Y3Vyc29y")))].into_iter()
+            .map(|(id, name, cursor)| PendingRepository { repository: repository(id, "octo-org", name), since, cursor, commits: Vec::new() })
+            .collect();
+        let (query, variables) = build_query(&pending);
+        assert!(query.starts_with("query($owner0: String!, $name0: String!, $since0: GitTimestamp!, $cursor0: String, $owner1: String!"), "{query}");
+        assert!(query.contains("  repository1: repository(owner: $owner1, name: $name1) {"), "{query}");
+        assert!(query.contains("history(first: 50, since: $since1, after: $cursor1) { ...History }"), "{query}");
+        assert!(query.contains("fragment History on CommitHistoryConnection"), "{query}");
+        assert_eq!(variables["name0"], json!("exporter"));
+        assert_eq!(variables["owner1"], json!("octo-org"));
+        assert_eq!(variables["since1"], json!("2023-05-20T10:00:00Z"));
+        assert_eq!(variables["cursor0"], Value::Null);
+        assert_eq!(variables["cursor1"], json!("Y3Vyc29y"));
+    }
+
+    #[test]
+    fn converts_commits_to_the_rest_structure() {
+        let node: CommitNode = serde_json::from_value(commit_node("c1")).expect("Invalid commit node");
+        let commit = to_full_commit_data("https://api.github.com", "octo-org/exporter", node);
+        assert_eq!(commit.commit.sha, "c1");
+        assert_eq!(commit.commit.url, "https://api.github.com/repos/octo-org/exporter/commits/c1");
+        assert_eq!(commit.commit.html_url, "https://github.com/octo-org/exporter/commit/c1");
+        assert_eq!(commit.commit.commit.comment_count, 3);
+        assert_eq!(commit.commit.commit.tree.url, "https://api.github.com/repos/octo-org/exporter/git/trees/t1");
+        assert!(commit.commit.commit.verification.verified);
+        assert_eq!(commit.commit.parents[0].url, "https://api.github.com/repos/octo-org/exporter/commits/p1");
+        let author = commit.commit.author.as_ref().expect("Author is not linked");
+        assert_eq!((author.login.as_str(), author.id, author.url.as_str()), ("octocat", 583231, "https://api.github.com/users/octocat"));
+        assert_eq!(commit.commit.commit.author.as_ref().map(|author| author.email.as_str()), Some("mona@github.com"));
+        assert!(commit.commit.committer.is_none());
+        assert_eq!((commit.changes.stats.additions, commit.changes.stats.deletions, commit.changes.stats.total), (10, 2, 12));
+        assert!(commit.changes.files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn maps_the_aliases_back_to_their_repositories() {
+        let base_url = start_mock_server(|_, _| {
+            json_response(StatusCode::OK, json!({
+                "data": { "repository0": history(vec![commit_node("c1")]), "repository1": null, "repository2": { "defaultBranchRef": null } },
+                "errors": [{ "message": "Could not resolve to a Repository with the name 'octo-org/deleted'.", "path": ["repository1"] }]
+            }))
+        });
+        let repositories = vec![repository(1, "octo-org", "exporter"), repository(2, "octo-org", "deleted"), repository(3, "octo-org", "empty")];
+        let results: HashMap<String, anyhow::Result<RepositoryAndCommits>> = get_batch_and_commits_since(&client(&base_url), repositories, &State::default()).await.into_iter().collect();
+        let exporter = results["octo-org/exporter"].as_ref().expect("Repository failed");
+        assert_eq!(exporter.repository.name, "exporter");
+        assert_eq!(exporter.commits.len(), 1);
+        let error = results["octo-org/deleted"].as_ref().expect_err("Missing repository did not fail");
+        assert!(error.to_string().contains("Could not resolve"), "{error}");
+        assert!(results["octo-org/empty"].as_ref().expect("Empty repository failed").commits.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failing_request_only_fails_its_own_repositories() {
+        let base_url = start_mock_server(|_, request| {
+            let names = queried_names(request.body());
+            if names.iter().any(|name| name == "broken") {
+                return json_response(StatusCode::BAD_GATEWAY, json!({ "message": "Server Error" }));
+            }
+            let data: Map<String, Value> = (0..names.len()).map(|index| (get_alias(index), history(vec![commit_node(&format!("c{index}"))]))).collect();
+            json_response(StatusCode::OK, json!({ "data": data }))
+        });
+        let repositories = vec![repository(1, "octo-org", "exporter"), repository(2, "octo-org", "broken"), repository(3, "octo-org", "dashboard")];
+        let results: HashMap<String, anyhow::Result<RepositoryAndCommits>> = get_batch_and_commits_since(&client(&base_url), repositories, &State::default()).await.into_iter().collect();
+        assert_eq!(results.len(), 3);
+        assert!(results["octo-org/broken"].is_err());
+        assert_eq!(results["octo-org/exporter"].as_ref().expect("Repository failed").commits.len(), 1);
+        assert_eq!(results["octo-org/dashboard"].as_ref().expect("Repository failed").commits.len(), 1);
+    }
+}
@@ -0,0 +1,168 @@
+use std::fs;
+use std::path::Path;
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use log::debug;
+use regex::Regex;
+use crate::data::Commit;
+
+lazy_static! {
+    /// `Proper Name <proper@email> Commit Name <commit@email>`, where everything but the last email is optional
+    static ref MAILMAP_ENTRY: Regex = Regex::new(r"^([^<]*)<([^>]*)>\s*(?:([^<]*)<([^>]*)>)?\s*$").expect("Invalid mailmap regex");
+}
+
+/// One line of a mailmap file, mapping the identity a commit was made with to the proper one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MailmapEntry {
+    proper_name: Option<String>,
+    proper_email: Option<String>,
+    commit_name: Option<String>,
+    commit_email: String,
+}
+
+/// Decides which author a commit is attributed to: the github login if the commit is linked to an
+/// account, otherwise the git author name or email. A mailmap file in the format of git
+/// (`git help mailmap`) merges identities that would otherwise be counted separately.
+#[derive(Debug, Clone, Default)]
+pub struct IdentityResolver {
+    entries: Vec<MailmapEntry>,
+}
+
+impl IdentityResolver {
+    pub fn from(mailmap: Option<&Path>) -> anyhow::Result<IdentityResolver> {
+        let path = match mailmap {
+            None => { return Ok(IdentityResolver::default()); }
+            Some(path) => { path }
+        };
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read mailmap '{path}': {e}", path = path.display()))?;
+        let resolver = IdentityResolver::parse(&content)
+            .map_err(|e| anyhow!("Invalid mailmap '{path}': {e}", path = path.display()))?;
+        debug!("Loaded {count} mailmap entries from {path}", count = resolver.entries.len(), path = path.display());
+        Ok(resolver)
+    }
+
+    pub fn parse(content: &str) -> anyhow::Result<IdentityResolver> {
+        let mut entries = Vec::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let captures = MAILMAP_ENTRY.captures(line)
+                .ok_or_else(|| anyhow!("Line {number} is not a mailmap entry: '{line}'", number = index + 1))?;
+            let get = |group: usize| captures.get(group).map(|value| value.as_str().trim().to_string()).filter(|value| !value.is_empty());
+            let entry = match (get(3), get(4)) {
+                // Only one email, which is the one the commit was made with
+                (None, None) => {
+                    MailmapEntry { proper_name: get(1), proper_email: None, commit_name: None, commit_email: get(2).unwrap_or_default() }
+                }
+                (commit_name, commit_email) => {
+                    MailmapEntry { proper_name: get(1), proper_email: get(2), commit_name, commit_email: commit_email.unwrap_or_default() }
+                }
+            };
+            entries.push(entry);
+        }
+        Ok(IdentityResolver { entries })
+    }
+
+    /// The name the commit is attributed to. A matching mailmap entry wins over the github login,
+    /// so that an account can be merged with commits not linked to it: its proper name if it has
+    /// one, otherwise its proper email. Commits made with a proper email listed in the mailmap are
+    /// attributed the same way, so that they end up with the commits mapped to that email.
+    pub fn resolve(&self, commit: &Commit) -> String {
+        let (name, email) = commit.commit.author.as_ref()
+            .map(|author| (author.name.as_str(), author.email.as_str()))
+            .unwrap_or(("", ""));
+        if let Some(entry) = self.find(name, email).or_else(|| self.find_proper(email)) {
+            match (&entry.proper_name, &entry.proper_email) {
+                (Some(proper_name), _) => { return proper_name.clone(); }
+                (None, Some(proper_email)) => { return proper_email.trim().to_lowercase(); }
+                (None, None) => {}
+            }
+        }
+        if let Some(user) = &commit.author {
+            return user.login.clone();
+        }
+        if !name.trim().is_empty() {
+            return name.trim().to_string();
+        }
+        match email.trim().is_empty() {
+            true => { String::from("unknown") }
+            false => { email.trim().to_lowercase() }
+        }
+    }
+
+    /// Like git, entries matching the name as well take precedence over those matching only the email.
+    fn find(&self, name: &str, email: &str) -> Option<&MailmapEntry> {
+        let matching_email = |entry: &&MailmapEntry| entry.commit_email.eq_ignore_ascii_case(email);
+        self.entries.iter()
+            .filter(matching_email)
+            .find(|entry| entry.commit_name.as_deref() == Some(name))
+            .or_else(|| self.entries.iter().filter(matching_email).find(|entry| entry.commit_name.is_none()))
+    }
+
+    /// The entry mapping other identities to the given proper email.
+    fn find_proper(&self, email: &str) -> Option<&MailmapEntry> {
+        self.entries.iter()
+            .find(|entry| entry.proper_email.as_deref().map(|proper_email| proper_email.eq_ignore_ascii_case(email)).unwrap_or(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::commit;
+
+    fn authored_by(name: &str, email: &str, has_account: bool) -> Commit {
+        let mut commit = commit("a1", "2023-05-20T10:00:00Z", 1, 0).commit;
+        if let Some(author) = commit.commit.author.as_mut() {
+            author.name = name.to_string();
+            author.email = email.to_string();
+        }
+        if !has_account {
+            commit.author = None;
+        }
+        commit
+    }
+
+    #[test]
+    fn prefers_the_login_and_falls_back_to_name_and_email() {
+        let resolver = IdentityResolver::default();
+        assert_eq!(resolver.resolve(&authored_by("Mona Octocat", "mona@github.com", true)), "octocat");
+        assert_eq!(resolver.resolve(&authored_by("Mona Octocat", "mona@github.com", false)), "Mona Octocat");
+        assert_eq!(resolver.resolve(&authored_by("", "Mona@GitHub.com", false)), "mona@github.com");
+        assert_eq!(resolver.resolve(&authored_by("", "", false)), "unknown");
+    }
+
+    #[test]
+    fn merges_identities_listed_in_the_mailmap() {
+        let resolver = IdentityResolver::parse("
+            # Proper identity followed by the identity the commits were made with
+            octocat <mona@github.com> <mona@laptop.local>
+            octocat <MONA@users.noreply.github.com>
+            <mona@github.com> <old@example.com>
+            Hubot <hubot@github.com> Hubot Bot <robot@example.com>
+        ").expect("Invalid mailmap");
+        assert_eq!(resolver.resolve(&authored_by("Mona", "mona@laptop.local", false)), "octocat");
+        assert_eq!(resolver.resolve(&authored_by("Mona", "mona@users.noreply.github.com", false)), "octocat");
+        assert_eq!(resolver.resolve(&authored_by("", "old@example.com", false)), "mona@github.com");
+        assert_eq!(resolver.resolve(&authored_by("Hubot Bot", "robot@example.com", true)), "Hubot");
+        // The name has to match as well if the entry lists one
+        assert_eq!(resolver.resolve(&authored_by("Someone", "robot@example.com", false)), "Someone");
+    }
+
+    #[test]
+    fn merges_names_through_an_email_only_entry() {
+        let resolver = IdentityResolver::parse("<mona@github.com> <mona@laptop.local>").expect("Invalid mailmap");
+        assert_eq!(resolver.resolve(&authored_by("Mona", "mona@laptop.local", false)), "mona@github.com");
+        assert_eq!(resolver.resolve(&authored_by("Mona Octocat", "Mona@GitHub.com", false)), "mona@github.com");
+        assert_eq!(resolver.resolve(&authored_by("Mona Octocat", "mona@github.com", true)), "mona@github.com");
+        assert_eq!(resolver.resolve(&authored_by("Hubot", "hubot@github.com", false)), "Hubot");
+    }
+
+    #[test]
+    fn rejects_lines_without_email() {
+        assert!(IdentityResolver::parse("octocat mona@github.com").is_err());
+    }
+}
@@ -0,0 +1,29 @@
+//! Fetches the repositories and commits of github organizations and users and turns them into
+//! prometheus metrics. Besides the exporter binary, other tools may reuse the github models in
+//! [data], the [client] and the metric extractors in [metrics].
+
+use std::time::SystemTime;
+use chrono::{DateTime, Utc};
+
+pub mod auth;
+pub mod bots;
+mod cache;
+pub mod client;
+pub mod config;
+pub mod data;
+pub mod error;
+#[cfg(test)]
+mod fixture;
+pub mod filter;
+mod graphql;
+pub mod identity;
+pub mod metrics;
+mod rate_limit;
+pub mod retry;
+pub mod source;
+pub mod state;
+pub mod sync;
+
+pub(crate) fn now() -> DateTime<Utc> {
+    DateTime::from(SystemTime::now())
+}
@@ -1,235 +1,206 @@
-use std::collections::HashMap;
 use std::{env, io};
+use std::collections::HashSet;
 use std::future::Future;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::ops::DerefMut;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::sync::{Arc, RwLock};
 use anyhow::anyhow;
-use cached::proc_macro::once;
 
-use chrono::{DateTime, LocalResult, TimeZone, Utc};
-use reqwest::{Client};
-use reqwest::header::{HeaderMap, HeaderValue};
-use serde_json::Error;
-use log::{debug, error, LevelFilter};
+use log::LevelFilter;
 use prometheus_client::registry::Registry;
-use lazy_static::lazy_static;
 use prometheus_client::encoding::text::encode;
 use tokio::signal::unix::{signal, SignalKind};
+use tokio::net::lookup_host;
+use tokio::sync::watch;
+use futures::future::try_join_all;
 use hyper::{
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server,
+    Body, Method, Request, Response, Server, StatusCode,
 };
 
-use data::*;
-use crate::metrics::create_metrics;
-
-mod data;
-mod metrics;
-
-lazy_static! {
-    static ref ORGANIZATION: String = env::var("ORG").expect("No organization provided for environment variable 'ORG'!");
-    static ref HEADERS: HeaderMap = create_default_headers(env::var("TOKEN").expect("No github-token provided for environment variable 'TOKEN'!")).expect("");
-    static ref PORT: u16 = env::var("PORT").expect("No port provided for environment variable 'PORT'!").parse::<u16>().expect("Not parsable to u16");
-    static ref LAST_SCRAPE: Mutex<DateTime<Utc>> = Mutex::new({
-        let last = Utc.with_ymd_and_hms(2007, 1, 1, 1, 1, 1);
-    let last = match last {
-        LocalResult::None => { panic!("No timestamp") }
-        LocalResult::Single(dt) => { dt }
-        LocalResult::Ambiguous(_, _) => { panic!("Ambiguous timestamp") }
-    };
-        last
-    });
-}
+use github_exporter_arm64_rs::bots::BotDetector;
+use github_exporter_arm64_rs::client::GitHubClient;
+use github_exporter_arm64_rs::config::{parse_config_path, Config};
+use github_exporter_arm64_rs::data::Snapshot;
+use github_exporter_arm64_rs::filter::RepositoryFilter;
+use github_exporter_arm64_rs::identity::IdentityResolver;
+use github_exporter_arm64_rs::metrics::{create_metrics, restore_repository_metrics};
+use github_exporter_arm64_rs::state::StateStore;
+use github_exporter_arm64_rs::sync::{poll_github, SyncScope};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     init_logging()?;
+    let config_path = parse_config_path(env::args().skip(1))?;
+    let config = Config::load(config_path.as_deref())?;
+    let github = GitHubClient::from(&config)?;
+    github.watch_token_file()?;
+    let filter = RepositoryFilter::from(&config.filters)?;
+    let identities = IdentityResolver::from(config.mailmap.as_deref())?;
+    let bots = BotDetector::from(&config.bots)?;
+    let store = StateStore::from(config.data_dir.as_deref())?;
+    let state = store.load()?;
+    // Resolve the listen addresses before starting to poll, so that invalid addresses fail the startup
+    let metrics_addrs = resolve_listen_addresses(config.listen_addr.as_str(), config.port).await?;
     let mut registry = <Registry>::default();
-    create_metrics(&mut registry);
-    let metrics_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), *PORT);
-    start_metrics_server(metrics_addr, registry).await;
-    Ok(())
+    let snapshot: Snapshot = Arc::new(RwLock::new(None));
+    create_metrics(&mut registry, snapshot.clone());
+    restore_repository_metrics(&state, &bots);
+    let scope = SyncScope { targets: config.targets(), filter, identities, bots };
+    tokio::spawn(poll_github(github, scope, config.poll_interval(), snapshot.clone(), store, state));
+    start_metrics_server(metrics_addrs, registry, snapshot).await
 }
 
-/// Start a HTTP server to report metrics.
-pub async fn start_metrics_server(metrics_addr: SocketAddr, registry: Registry) {
-    let mut shutdown_stream = signal(SignalKind::terminate()).unwrap();
+/// Resolves a comma separated list of listen addresses. Every entry may be an IPv4 or IPv6
+/// address or a hostname, optionally followed by a port (`[::1]:9090`, `localhost:9090`);
+/// entries without a port use `default_port`, which is required for them.
+async fn resolve_listen_addresses(listen_addr: &str, default_port: Option<u16>) -> anyhow::Result<Vec<SocketAddr>> {
+    let mut addresses = Vec::new();
+    for entry in listen_addr.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let default_port = || default_port.ok_or_else(|| anyhow!("No port configured for the listen address '{entry}'"));
+        if let Ok(address) = entry.parse::<SocketAddr>() {
+            addresses.push(address);
+            continue;
+        }
+        if let Ok(ip) = entry.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            addresses.push(SocketAddr::new(ip, default_port()?));
+            continue;
+        }
+        let (host, port) = match entry.rsplit_once(':') {
+            Some((host, port)) => { (host, port.parse::<u16>().map_err(|_| anyhow!("Invalid port in listen address '{entry}'"))?) }
+            None => { (entry, default_port()?) }
+        };
+        let resolved: Vec<SocketAddr> = lookup_host((host, port)).await
+            .map_err(|e| anyhow!("Failed to resolve listen address '{entry}': {e}"))?
+            .collect();
+        if resolved.is_empty() {
+            return Err(anyhow!("Listen address '{entry}' did not resolve to any address"));
+        }
+        addresses.extend(resolved);
+    }
+    if addresses.is_empty() {
+        return Err(anyhow!("No listen address provided"));
+    }
+    // Binding the same address twice would fail, hostnames may also resolve to listed addresses
+    let mut seen = HashSet::new();
+    addresses.retain(|address| seen.insert(*address));
+    Ok(addresses)
+}
 
-    eprintln!("Starting metrics server on {metrics_addr}");
+/// Start a HTTP server on every given address to report metrics.
+pub async fn start_metrics_server(metrics_addrs: Vec<SocketAddr>, registry: Registry, snapshot: Snapshot) -> anyhow::Result<()> {
+    let mut shutdown_stream = signal(SignalKind::terminate())?;
+    let (shutdown_sender, shutdown_receiver) = watch::channel(());
+    tokio::spawn(async move {
+        shutdown_stream.recv().await;
+        let _ = shutdown_sender.send(());
+    });
 
     let registry = Arc::new(registry);
-    Server::bind(&metrics_addr)
-        .serve(make_service_fn(move |_conn| {
-            let registry = registry.clone();
-            async move {
-                let handler = make_handler(registry);
-                Ok::<_, io::Error>(service_fn(handler))
-            }
-        }))
-        .with_graceful_shutdown(async move {
-            shutdown_stream.recv().await;
-        })
-        .await
-        .unwrap();
+    let mut servers = Vec::new();
+    for metrics_addr in metrics_addrs {
+        eprintln!("Starting metrics server on {metrics_addr}");
+        let registry = registry.clone();
+        let snapshot = snapshot.clone();
+        let mut shutdown_receiver = shutdown_receiver.clone();
+        let server = Server::try_bind(&metrics_addr)
+            .map_err(|e| anyhow!("Failed to bind metrics server to {metrics_addr}: {e}"))?
+            .serve(make_service_fn(move |_conn| {
+                let registry = registry.clone();
+                let snapshot = snapshot.clone();
+                async move {
+                    let handler = make_handler(registry, snapshot);
+                    Ok::<_, io::Error>(service_fn(handler))
+                }
+            }))
+            .with_graceful_shutdown(async move {
+                let _ = shutdown_receiver.changed().await;
+            });
+        servers.push(server);
+    }
+    try_join_all(servers).await?;
+    Ok(())
 }
 
+type ResponseFuture = Pin<Box<dyn Future<Output = io::Result<Response<Body>>> + Send>>;
+
+const INDEX_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>GitHub Exporter</title></head>
+<body>
+<h1>GitHub Exporter</h1>
+<ul>
+<li><a href="/metrics">Metrics</a></li>
+<li><a href="/healthz">Health</a></li>
+<li><a href="/ready">Readiness</a></li>
+</ul>
+</body>
+</html>
+"#;
+
 /// This function returns a HTTP handler (i.e. another function)
 pub fn make_handler(
     registry: Arc<Registry>,
-) -> impl Fn(Request<Body>) -> Pin<Box<dyn Future<Output = io::Result<Response<Body>>> + Send>> {
-    // This closure accepts a request and responds with the OpenMetrics encoding of our metrics.
-    move |_req: Request<Body>| {
+    snapshot: Snapshot,
+) -> impl Fn(Request<Body>) -> ResponseFuture {
+    // This closure accepts a request and routes it to the metrics, health or index page.
+    move |req: Request<Body>| {
         let reg = registry.clone();
+        let snapshot = snapshot.clone();
         Box::pin(async move {
-            let mut buf = String::new();
-            encode(&mut buf, &reg.clone())
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
-                .map(|_| {
-                    let body = Body::from(buf);
-                    Response::builder()
-                        .header(
-                            hyper::header::CONTENT_TYPE,
-                            "application/openmetrics-text; version=1.0.0; charset=utf-8",
-                        )
-                        .body(body)
-                        .unwrap()
-                })
+            let is_known_path = matches!(req.uri().path(), "/" | "/metrics" | "/healthz" | "/ready");
+            if is_known_path && req.method() != Method::GET && req.method() != Method::HEAD {
+                return Ok(Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .header(hyper::header::ALLOW, "GET, HEAD")
+                    .body(Body::from("Method Not Allowed\n"))
+                    .unwrap());
+            }
+            match req.uri().path() {
+                "/metrics" => { encode_metrics(&reg) }
+                "/healthz" => { Ok(text_response(StatusCode::OK, "OK\n")) }
+                "/ready" => {
+                    let is_ready = snapshot.read().map(|guard| guard.is_some()).unwrap_or(false);
+                    match is_ready {
+                        true => { Ok(text_response(StatusCode::OK, "Ready\n")) }
+                        false => { Ok(text_response(StatusCode::SERVICE_UNAVAILABLE, "Waiting for the first sync with github\n")) }
+                    }
+                }
+                "/" => {
+                    Ok(Response::builder()
+                        .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+                        .body(Body::from(INDEX_PAGE))
+                        .unwrap())
+                }
+                _ => { Ok(text_response(StatusCode::NOT_FOUND, "Not Found\n")) }
+            }
         })
     }
 }
 
-fn now() -> DateTime<Utc> {
-    DateTime::from(SystemTime::now())
-}
-
-#[once(time = 10, option = true, sync_writes = true)]
-pub async fn get_all_commits_since_last_and_update_timestamp() -> Option<RepositoriesWithCommits> {
-    let client = Client::new();
-    let now: DateTime<Utc> = DateTime::from(SystemTime::now()); //for whatever reason calling now() would result in a compiler error due to the used macro
-    let last_scrape = match LAST_SCRAPE.lock() {
-        Ok(guard) => { (*guard).into() }
-        Err(_) => {
-            error!("Failed to acquire mutex gard of last scrape!");
-            return None;
-        }
-    };
-    let result = get_all_commits_since(&client, &HEADERS, ORGANIZATION.as_str(), last_scrape).await;
-    let data = match result {
-        Ok(value) => { Some(RepositoriesWithCommits { data: value }) }
-        Err(error) => {
-            error!("Some error occurred during fetching of data from github {error}");
-            None
-        }
-    };
-    let mut last_scrape_ref = LAST_SCRAPE.lock().unwrap();
-    *last_scrape_ref.deref_mut() = now;
-    data
-}
-
-async fn get_all_commits_since(client: &Client, headers: &HeaderMap, organization: &str, since: DateTime<Utc>) -> anyhow::Result<Vec<RepositoryAndCommits>> {
-    let repositories = list_organization_repositories(&client, headers.clone(), organization).await?;
-    let mut data = Vec::new();
-    for repository in repositories {
-        debug!("Fetching commits for {repo}...", repo=&repository.name);
-        let commits = list_commits_in_repository_since(&client, repository.full_name.clone(), headers.clone(), since).await?;
-        let mut full_data = Vec::new();
-        for commit in commits {
-            debug!("Fetching details for {commit}...", commit=&commit.commit.message);
-            let full_commit_data = get_full_commit_data(&client, headers.clone(), &repository.full_name.clone(), commit).await?;
-            full_data.push(full_commit_data);
-        }
-        let repository_and_commits = RepositoryAndCommits::from(repository, full_data);
-        data.push(repository_and_commits);
-    }
-    Ok(data)
-}
-
-async fn get_full_commit_data(client: &Client, headers: HeaderMap, full_repository_name: &str, commit: Commit) -> anyhow::Result<FullCommitData> {
-    let details = fetch_commit(&client, headers.clone(), full_repository_name, commit.sha.as_str()).await?;
-    Ok(FullCommitData::from(commit, details))
-}
-
-async fn list_organization_repositories(client: &Client, headers: HeaderMap, organization: &str) -> anyhow::Result<Vec<MinimalRepository>> {
-    let url = format!("https://api.github.com/orgs/{organization}/repos", organization = organization);
-    let response = client
-        .get(url)
-        .headers(headers)
-        .send()
-        .await?;
-    let status_code = get_status_code(&response);
-    debug!("Retrieving all repositories of {org} - Status code: {code}", org = organization, code = status_code);
-    let json_string = response.text().await?;
-    let conversion_result: Result<Vec<MinimalRepository>, _> = serde_json::from_str(&json_string);
-    let repositories = handle_json_conversion(status_code, json_string, conversion_result);
-    Ok(repositories)
-}
-
-fn get_status_code(response: &reqwest::Response) -> u16 {
-    let status_code = &response.status().clone();
-    let status_code = status_code.as_u16();
-    status_code
-}
-
-async fn list_commits_in_repository_since(client: &Client, full_repository_name: String, headers: HeaderMap, since: DateTime<Utc>) -> anyhow::Result<Vec<Commit>> {
-    let mut params = HashMap::new();
-    params.insert("since", since.to_string());
-    let url = format!("https://api.github.com/repos/{full_name}/commits", full_name = full_repository_name);
-    let response = client.get(url)
-        .query(&params)
-        .headers(headers)
-        .send()
-        .await?;
-    let status_code = get_status_code(&response);
-    debug!("Retrieving all commits of {repo} - Status code: {code}", repo = full_repository_name, code = status_code);
-    let json_string = response.text().await?;
-    let conversion_result: Result<Vec<Commit>, _> = serde_json::from_str(&json_string);
-    let commits = handle_json_conversion(status_code, json_string, conversion_result);
-    Ok(commits)
-}
-
-async fn fetch_commit(client: &Client, headers: HeaderMap, full_repository_name: &str, commit_reference: &str) -> anyhow::Result<CommitChangeDetails> {
-    let url = format!("https://api.github.com/repos/{full_name}/commits/{reference}", full_name = full_repository_name, reference = commit_reference);
-    let response = client.get(url)
-        .headers(headers)
-        .send()
-        .await?;
-    let status_code = get_status_code(&response);
-    debug!("Retrieving all details of commit {commit} - Status code: {code}", commit = commit_reference, code = status_code);
-    let json_string = response.text().await?;
-    let conversion_result: Result<CommitChangeDetails, _> = serde_json::from_str(&json_string);
-    let commit_details = match conversion_result {
-        Ok(details) => { details }
-        Err(e) => {
-            error!("Failed to deserialize JSON data: {error}", error = e);
-            error!("JSON data: {json}", json = json_string);
-            error!("{}", status_code);
-            return Err(anyhow!(e));
-        }
-    };
-    Ok(commit_details)
-}
-
-fn handle_json_conversion<Type>(status_code: u16, json_string: String, conversion_result: Result<Vec<Type>, Error>) -> Vec<Type> {
-    match conversion_result {
-        Ok(conversions) => conversions,
-        Err(e) => {
-            error!("Failed to deserialize JSON data: {error}", error = e);
-            error!("JSON data: {json}", json = json_string);
-            error!("{}", status_code);
-            Vec::new()
-        }
-    }
+/// Responds with the OpenMetrics encoding of our metrics.
+fn encode_metrics(registry: &Registry) -> io::Result<Response<Body>> {
+    let mut buf = String::new();
+    encode(&mut buf, registry)
+        .map_err(std::io::Error::other)
+        .map(|_| {
+            let body = Body::from(buf);
+            Response::builder()
+                .header(
+                    hyper::header::CONTENT_TYPE,
+                    "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                )
+                .body(body)
+                .unwrap()
+        })
 }
 
-fn create_default_headers(token: String) -> anyhow::Result<HeaderMap> {
-    let mut headers = HeaderMap::new();
-    headers.insert("Accept", HeaderValue::from_static("application/vnd.github+json"));
-    headers.insert("User-Agent", HeaderValue::from_static("github-exporter-arm64-rs"));
-    headers.insert("Authorization", HeaderValue::from_str(format!("Bearer {token}").as_str())?);
-    Ok(headers)
+fn text_response(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap()
 }
 
 fn init_logging() -> anyhow::Result<()> {
@@ -249,3 +220,36 @@ fn init_logging() -> anyhow::Result<()> {
         .apply()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolves_addresses_with_and_without_ports() {
+        let addresses = resolve_listen_addresses("127.0.0.1, [::1]:9100, ::1", Some(9090)).await.expect("Invalid listen addresses");
+        let expected: Vec<SocketAddr> = vec!["127.0.0.1:9090".parse().unwrap(), "[::1]:9100".parse().unwrap(), "[::1]:9090".parse().unwrap()];
+        assert_eq!(addresses, expected);
+    }
+
+    #[tokio::test]
+    async fn does_not_need_a_default_port_if_every_address_has_one() {
+        let addresses = resolve_listen_addresses("127.0.0.1:9100,[::1]:9100", None).await.expect("Invalid listen addresses");
+        let expected: Vec<SocketAddr> = vec!["127.0.0.1:9100".parse().unwrap(), "[::1]:9100".parse().unwrap()];
+        assert_eq!(addresses, expected);
+    }
+
+    #[tokio::test]
+    async fn removes_duplicate_addresses_that_are_not_adjacent() {
+        let addresses = resolve_listen_addresses("127.0.0.1,[::1],127.0.0.1:9090", Some(9090)).await.expect("Invalid listen addresses");
+        let expected: Vec<SocketAddr> = vec!["127.0.0.1:9090".parse().unwrap(), "[::1]:9090".parse().unwrap()];
+        assert_eq!(addresses, expected);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_listen_addresses() {
+        assert!(resolve_listen_addresses("localhost:metrics", Some(9090)).await.is_err());
+        assert!(resolve_listen_addresses(" , ", Some(9090)).await.is_err());
+        assert!(resolve_listen_addresses("127.0.0.1:9100,::1", None).await.is_err());
+    }
+}
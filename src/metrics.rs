@@ -1,21 +1,116 @@
+//! The extractors count every commit of the data they are given, bots included. To count bot
+//! commits as configured, extract each part returned by [BotDetector::partition] separately, as
+//! [record_repository_metrics] does.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::fmt::Error;
-use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue, EncodeMetric, MetricEncoder};
+use prometheus_client::encoding::{EncodeLabelSet, EncodeMetric, LabelSetEncoder, MetricEncoder};
 use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::metrics::MetricType;
 use prometheus_client::registry::Registry;
-use crate::data::{RepositoriesWithCommits};
-use crate::get_all_commits_since_last_and_update_timestamp;
-use futures::executor::block_on;
-use log::debug;
+use crate::bots::BotDetector;
+use crate::data::{FullCommitData, MinimalRepository, RepositoriesWithCommits, Snapshot};
+use crate::identity::IdentityResolver;
+use crate::state::State;
+use lazy_static::lazy_static;
+use log::{debug, error};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ApiErrorLabels {
+    pub endpoint: String,
+    pub kind: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct RateLimitLabels {
+    pub resource: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct OwnerLabels {
+    pub owner: String,
+}
+
+/// The `is_bot` label is only encoded if bot commits are labeled, see [BotDetector::label].
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct RepositoryLabels {
+    pub owner: String,
+    pub repository: String,
+    pub is_bot: Option<bool>,
+}
+
+impl EncodeLabelSet for RepositoryLabels {
+    fn encode(&self, encoder: LabelSetEncoder) -> Result<(), Error> {
+        let mut labels = vec![("owner", self.owner.as_str()), ("repository", self.repository.as_str())];
+        labels.extend(encode_is_bot(self.is_bot));
+        labels.encode(encoder)
+    }
+}
+
+/// The `is_bot` label is only encoded if bot commits are labeled, see [BotDetector::label].
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct AuthorLabels {
+    pub owner: String,
+    pub repository: String,
+    pub author: String,
+    pub is_bot: Option<bool>,
+}
+
+impl EncodeLabelSet for AuthorLabels {
+    fn encode(&self, encoder: LabelSetEncoder) -> Result<(), Error> {
+        let mut labels = vec![("owner", self.owner.as_str()), ("repository", self.repository.as_str()), ("author", self.author.as_str())];
+        labels.extend(encode_is_bot(self.is_bot));
+        labels.encode(encoder)
+    }
+}
+
+fn encode_is_bot(is_bot: Option<bool>) -> Option<(&'static str, &'static str)> {
+    match is_bot {
+        None => { None }
+        Some(true) => { Some(("is_bot", "true")) }
+        Some(false) => { Some(("is_bot", "false")) }
+    }
+}
+
+lazy_static! {
+    static ref COMMITS_PER_REPOSITORY: Family<RepositoryLabels, Counter> = Family::default();
+    static ref LINES_ADDED_PER_REPOSITORY: Family<RepositoryLabels, Counter> = Family::default();
+    static ref LINES_DELETED_PER_REPOSITORY: Family<RepositoryLabels, Counter> = Family::default();
+    static ref COMMITS_PER_AUTHOR: Family<AuthorLabels, Counter> = Family::default();
+    static ref LINES_ADDED_PER_AUTHOR: Family<AuthorLabels, Counter> = Family::default();
+    static ref LINES_DELETED_PER_AUTHOR: Family<AuthorLabels, Counter> = Family::default();
+    static ref API_ERRORS: Family<ApiErrorLabels, Counter> = Family::default();
+    static ref RATE_LIMIT_REMAINING: Family<RateLimitLabels, Gauge> = Family::default();
+    static ref RATE_LIMIT_LIMIT: Family<RateLimitLabels, Gauge> = Family::default();
+    static ref RATE_LIMIT_RESET_TIMESTAMP: Family<RateLimitLabels, Gauge> = Family::default();
+}
 
 pub fn extract_number_of_repositories(data: &RepositoriesWithCommits) -> i128 {
     data.data.len() as i128
 }
 
-pub fn extract_number_of_commits_per_repository(data: &RepositoriesWithCommits) -> Vec<(String, i128)> {
+/// Counts the repositories per owner, in the order the owners appear first.
+pub fn count_repositories_per_owner<'a>(repositories: impl IntoIterator<Item = &'a MinimalRepository>) -> Vec<(String, i128)> {
+    let mut repositories_per_owner: Vec<(String, i128)> = Vec::new();
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for repository in repositories {
+        match positions.entry(repository.owner.login.as_str()) {
+            Entry::Occupied(position) => { repositories_per_owner[*position.get()].1 += 1; }
+            Entry::Vacant(position) => {
+                position.insert(repositories_per_owner.len());
+                repositories_per_owner.push((repository.owner.login.clone(), 1));
+            }
+        }
+    }
+    repositories_per_owner
+}
+
+pub fn extract_number_of_commits_per_repository(data: &RepositoriesWithCommits) -> Vec<(String, String, i128)> {
     data.data.iter().map(|value| {
-        (value.repository.name.clone(), value.commits.len() as i128)
+        (value.repository.owner.login.clone(), value.repository.name.clone(), value.commits.len() as i128)
     }).collect()
 }
 
@@ -51,6 +146,57 @@ pub fn extract_number_of_deletions_per_commit(data: &RepositoriesWithCommits) ->
         }).collect()
 }
 
+pub fn extract_number_of_additions_per_repository(data: &RepositoriesWithCommits) -> Vec<(String, String, i128)> {
+    data.data.iter().map(|value| {
+        let additions = value.commits.iter()
+            .map(|commit| commit.changes.stats.additions as i128).sum();
+        (value.repository.owner.login.clone(), value.repository.name.clone(), additions)
+    }).collect()
+}
+
+pub fn extract_number_of_deletions_per_repository(data: &RepositoriesWithCommits) -> Vec<(String, String, i128)> {
+    data.data.iter().map(|value| {
+        let deletions = value.commits.iter()
+            .map(|commit| commit.changes.stats.deletions as i128).sum();
+        (value.repository.owner.login.clone(), value.repository.name.clone(), deletions)
+    }).collect()
+}
+
+pub fn extract_number_of_commits_per_author(data: &RepositoriesWithCommits, identities: &IdentityResolver) -> Vec<(String, String, String, i128)> {
+    extract_per_author(data, identities, |_| 1)
+}
+
+pub fn extract_number_of_additions_per_author(data: &RepositoriesWithCommits, identities: &IdentityResolver) -> Vec<(String, String, String, i128)> {
+    extract_per_author(data, identities, |commit| commit.changes.stats.additions as i128)
+}
+
+pub fn extract_number_of_deletions_per_author(data: &RepositoriesWithCommits, identities: &IdentityResolver) -> Vec<(String, String, String, i128)> {
+    extract_per_author(data, identities, |commit| commit.changes.stats.deletions as i128)
+}
+
+/// Sums up the value of every commit per owner, repository and author, in the order the authors
+/// appear first.
+fn extract_per_author(data: &RepositoriesWithCommits, identities: &IdentityResolver, value: impl Fn(&FullCommitData) -> i128) -> Vec<(String, String, String, i128)> {
+    let mut values_per_author: Vec<(String, String, String, i128)> = Vec::new();
+    let mut positions: HashMap<(&str, &str, String), usize> = HashMap::new();
+    for repository in &data.data {
+        let owner = repository.repository.owner.login.as_str();
+        let name = repository.repository.name.as_str();
+        for commit in &repository.commits {
+            let author = identities.resolve(&commit.commit);
+            match positions.entry((owner, name, author)) {
+                Entry::Occupied(position) => { values_per_author[*position.get()].3 += value(commit); }
+                Entry::Vacant(position) => {
+                    let author = position.key().2.clone();
+                    position.insert(values_per_author.len());
+                    values_per_author.push((owner.to_string(), name.to_string(), author, value(commit)));
+                }
+            }
+        }
+    }
+    values_per_author
+}
+
 pub fn extract_total_number_of_additions(data: &RepositoriesWithCommits) -> i128 {
     data.data.iter()
         .map(|repository| &repository.commits)
@@ -65,22 +211,123 @@ pub fn extract_total_number_of_deletions(data: &RepositoriesWithCommits) -> i128
             .map(|commit| commit.changes.stats.deletions as i128).sum::<i128>()).sum()
 }
 
-pub fn create_metrics(registry: &mut Registry) {
+/// Adds the commits fetched since the last scrape to the per-repository counters. As only the
+/// incremental data is passed in, the counters accumulate across scrapes. Bot commits are
+/// counted, dropped or labeled as configured for the [BotDetector].
+pub fn record_repository_metrics(data: &RepositoriesWithCommits, identities: &IdentityResolver, bots: &BotDetector) {
+    for (is_bot, data) in bots.partition(data) {
+        increment_per_repository(&COMMITS_PER_REPOSITORY, is_bot, extract_number_of_commits_per_repository(&data));
+        increment_per_repository(&LINES_ADDED_PER_REPOSITORY, is_bot, extract_number_of_additions_per_repository(&data));
+        increment_per_repository(&LINES_DELETED_PER_REPOSITORY, is_bot, extract_number_of_deletions_per_repository(&data));
+        increment_per_author(&COMMITS_PER_AUTHOR, is_bot, extract_number_of_commits_per_author(&data, identities));
+        increment_per_author(&LINES_ADDED_PER_AUTHOR, is_bot, extract_number_of_additions_per_author(&data, identities));
+        increment_per_author(&LINES_DELETED_PER_AUTHOR, is_bot, extract_number_of_deletions_per_author(&data, identities));
+    }
+}
+
+/// Initializes the per-repository counters with the values persisted before the last restart.
+/// The bot counters only exist if bot commits were labeled when they were synced.
+pub fn restore_repository_metrics(state: &State, bots: &BotDetector) {
+    for repository in state.repositories.values() {
+        let labels = RepositoryLabels { owner: repository.owner.clone(), repository: repository.name.clone(), is_bot: bots.label(false) };
+        COMMITS_PER_REPOSITORY.get_or_create(&labels).inc_by(repository.commits);
+        LINES_ADDED_PER_REPOSITORY.get_or_create(&labels).inc_by(repository.additions);
+        LINES_DELETED_PER_REPOSITORY.get_or_create(&labels).inc_by(repository.deletions);
+        if let Some(is_bot) = bots.label(true) {
+            let labels = RepositoryLabels { is_bot: Some(is_bot), ..labels };
+            COMMITS_PER_REPOSITORY.get_or_create(&labels).inc_by(repository.bot_commits);
+            LINES_ADDED_PER_REPOSITORY.get_or_create(&labels).inc_by(repository.bot_additions);
+            LINES_DELETED_PER_REPOSITORY.get_or_create(&labels).inc_by(repository.bot_deletions);
+        }
+        for (author, values) in &repository.authors {
+            let labels = AuthorLabels { owner: repository.owner.clone(), repository: repository.name.clone(), author: author.clone(), is_bot: bots.label(values.is_bot) };
+            COMMITS_PER_AUTHOR.get_or_create(&labels).inc_by(values.commits);
+            LINES_ADDED_PER_AUTHOR.get_or_create(&labels).inc_by(values.additions);
+            LINES_DELETED_PER_AUTHOR.get_or_create(&labels).inc_by(values.deletions);
+        }
+    }
+}
+
+fn increment_per_repository(family: &Family<RepositoryLabels, Counter>, is_bot: Option<bool>, values: Vec<(String, String, i128)>) {
+    for (owner, repository, value) in values {
+        family
+            .get_or_create(&RepositoryLabels { owner, repository, is_bot })
+            .inc_by(value.max(0) as u64);
+    }
+}
+
+fn increment_per_author(family: &Family<AuthorLabels, Counter>, is_bot: Option<bool>, values: Vec<(String, String, String, i128)>) {
+    for (owner, repository, author, value) in values {
+        family
+            .get_or_create(&AuthorLabels { owner, repository, author, is_bot })
+            .inc_by(value.max(0) as u64);
+    }
+}
+
+/// Exports the rate limit budget of the github token for a resource like `core` or `graphql` as
+/// last reported by the api.
+pub fn record_rate_limit(resource: &str, limit: Option<i64>, remaining: Option<i64>, reset_timestamp: Option<i64>) {
+    let labels = RateLimitLabels { resource: resource.to_string() };
+    if let Some(limit) = limit {
+        RATE_LIMIT_LIMIT.get_or_create(&labels).set(limit);
+    }
+    if let Some(remaining) = remaining {
+        RATE_LIMIT_REMAINING.get_or_create(&labels).set(remaining);
+    }
+    if let Some(reset_timestamp) = reset_timestamp {
+        RATE_LIMIT_RESET_TIMESTAMP.get_or_create(&labels).set(reset_timestamp);
+    }
+}
+
+pub fn record_api_error(endpoint: &str, kind: &str) {
+    API_ERRORS
+        .get_or_create(&ApiErrorLabels { endpoint: endpoint.to_string(), kind: kind.to_string() })
+        .inc();
+}
+
+pub fn create_metrics(registry: &mut Registry, snapshot: Snapshot) {
     debug!("Registration of Repository Count metric...");
-    registry.register("repositoryCount", "Current total number of repositories", RepositoryCountMetric {})
+    registry.register("repositoryCount", "Current number of repositories per owner", RepositoryCountMetric { snapshot });
+    debug!("Registration of Commits per Repository metric...");
+    registry.register("github_commits", "Number of commits per repository", COMMITS_PER_REPOSITORY.clone());
+    debug!("Registration of Lines Added/Deleted per Repository metrics...");
+    registry.register("github_lines_added", "Number of lines added per repository", LINES_ADDED_PER_REPOSITORY.clone());
+    registry.register("github_lines_deleted", "Number of lines deleted per repository", LINES_DELETED_PER_REPOSITORY.clone());
+    debug!("Registration of per Author metrics...");
+    registry.register("github_commits_by_author", "Number of commits per repository and author", COMMITS_PER_AUTHOR.clone());
+    registry.register("github_lines_added_by_author", "Number of lines added per repository and author", LINES_ADDED_PER_AUTHOR.clone());
+    registry.register("github_lines_deleted_by_author", "Number of lines deleted per repository and author", LINES_DELETED_PER_AUTHOR.clone());
+    debug!("Registration of Api Errors metric...");
+    registry.register("github_exporter_api_errors", "Number of failed requests to the github api per endpoint and kind of error", API_ERRORS.clone());
+    debug!("Registration of Rate Limit metrics...");
+    registry.register("github_exporter_rate_limit_remaining", "Number of requests remaining in the current rate limit window per resource", RATE_LIMIT_REMAINING.clone());
+    registry.register("github_exporter_rate_limit_limit", "Maximum number of requests per rate limit window per resource", RATE_LIMIT_LIMIT.clone());
+    registry.register("github_exporter_rate_limit_reset_timestamp", "Unix timestamp at which the current rate limit window of the resource resets", RATE_LIMIT_RESET_TIMESTAMP.clone());
 }
 
 #[derive(Debug)]
-struct RepositoryCountMetric {}
+struct RepositoryCountMetric {
+    snapshot: Snapshot,
+}
 
 impl EncodeMetric for RepositoryCountMetric {
     fn encode(&self, mut encoder: MetricEncoder<'_, '_>) -> Result<(), Error> {
-        let results = block_on(get_all_commits_since_last_and_update_timestamp());
-        match results {
-            None => { Err(Error {}) }
-            Some(data) => {
-                let repositories = extract_number_of_repositories(&data) as u64;
-                encoder.encode_counter::<(), _, u64>(&repositories, None)
+        let guard = match self.snapshot.read() {
+            Ok(guard) => { guard }
+            Err(_) => {
+                error!("Failed to acquire lock of the snapshot!");
+                return Err(Error {});
+            }
+        };
+        match guard.as_ref() {
+            // Nothing has been synced yet, so there is no value to report
+            None => { Ok(()) }
+            Some(repositories) => {
+                for (owner, repositories) in count_repositories_per_owner(repositories) {
+                    encoder.encode_family(&OwnerLabels { owner })?
+                        .encode_gauge(&(repositories as i64))?;
+                }
+                Ok(())
             }
         }
     }
@@ -88,4 +335,93 @@ impl EncodeMetric for RepositoryCountMetric {
     fn metric_type(&self) -> MetricType {
         MetricType::Gauge
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bots::{BotConfig, BotMode};
+    use crate::data::RepositoryAndCommits;
+    use crate::fixture::{commit, repository};
+
+    fn data() -> RepositoriesWithCommits {
+        RepositoriesWithCommits {
+            data: vec![
+                RepositoryAndCommits::from(repository(1, "octo-org", "exporter"), vec![
+                    commit("a1", "2023-05-20T10:00:00Z", 10, 2),
+                    commit("a2", "2023-05-21T10:00:00Z", 5, 1),
+                ]),
+                RepositoryAndCommits::from(repository(2, "octo-org", "dashboard"), Vec::new()),
+                RepositoryAndCommits::from(repository(3, "octocat", "dotfiles"), vec![
+                    commit("c1", "2023-05-19T08:00:00Z", 7, 0),
+                ]),
+            ],
+        }
+    }
+
+    #[test]
+    fn counts_repositories_per_owner() {
+        let data = data();
+        let repositories = count_repositories_per_owner(data.data.iter().map(|value| &value.repository));
+        assert_eq!(repositories, vec![(String::from("octo-org"), 2), (String::from("octocat"), 1)]);
+    }
+
+    #[test]
+    fn extracts_commits_and_lines_per_repository() {
+        let data = data();
+        let expected_commits = vec![
+            (String::from("octo-org"), String::from("exporter"), 2),
+            (String::from("octo-org"), String::from("dashboard"), 0),
+            (String::from("octocat"), String::from("dotfiles"), 1),
+        ];
+        assert_eq!(extract_number_of_commits_per_repository(&data), expected_commits);
+        let additions: Vec<i128> = extract_number_of_additions_per_repository(&data).into_iter().map(|(_, _, additions)| additions).collect();
+        assert_eq!(additions, vec![15, 0, 7]);
+        let deletions: Vec<i128> = extract_number_of_deletions_per_repository(&data).into_iter().map(|(_, _, deletions)| deletions).collect();
+        assert_eq!(deletions, vec![3, 0, 0]);
+    }
+
+    #[test]
+    fn extracts_commits_and_lines_per_author() {
+        let mut data = data();
+        // Not linked to a github account, so the git author name is used
+        data.data[0].commits[1].commit.author = None;
+        let identities = IdentityResolver::default();
+        let expected_commits = vec![
+            (String::from("octo-org"), String::from("exporter"), String::from("octocat"), 1),
+            (String::from("octo-org"), String::from("exporter"), String::from("Mona Octocat"), 1),
+            (String::from("octocat"), String::from("dotfiles"), String::from("octocat"), 1),
+        ];
+        assert_eq!(extract_number_of_commits_per_author(&data, &identities), expected_commits);
+        let additions: Vec<i128> = extract_number_of_additions_per_author(&data, &identities).into_iter().map(|(_, _, _, additions)| additions).collect();
+        assert_eq!(additions, vec![10, 5, 7]);
+        let identities = IdentityResolver::parse("octocat <mona@github.com>").expect("Invalid mailmap");
+        let commits: Vec<i128> = extract_number_of_commits_per_author(&data, &identities).into_iter().map(|(_, _, _, commits)| commits).collect();
+        assert_eq!(commits, vec![2, 1]);
+    }
+
+    #[test]
+    fn extracts_the_parts_of_a_bot_partition_separately() {
+        let mut data = data();
+        data.data[0].commits[1].commit.author.as_mut().expect("Commit without author").login = String::from("dependabot[bot]");
+        assert_eq!(extract_total_number_of_commits(&data), 3);
+        let bots = BotDetector::from(&BotConfig { mode: BotMode::Label, ..BotConfig::default() }).expect("Invalid bot config");
+        let totals: Vec<(Option<bool>, i128, i128)> = bots.partition(&data).into_iter()
+            .map(|(is_bot, part)| (is_bot, extract_total_number_of_commits(&part), extract_total_number_of_additions(&part)))
+            .collect();
+        assert_eq!(totals, vec![(Some(false), 2, 17), (Some(true), 1, 5)]);
+    }
+
+    #[test]
+    fn encodes_is_bot_label_only_if_bots_are_labeled() {
+        let family: Family<RepositoryLabels, Counter> = Family::default();
+        family.get_or_create(&RepositoryLabels { owner: String::from("octo-org"), repository: String::from("exporter"), is_bot: None }).inc();
+        family.get_or_create(&RepositoryLabels { owner: String::from("octo-org"), repository: String::from("dashboard"), is_bot: Some(true) }).inc();
+        let mut registry = <Registry>::default();
+        registry.register("github_commits", "Number of commits per repository", family);
+        let mut encoded = String::new();
+        prometheus_client::encoding::text::encode(&mut encoded, &registry).expect("Failed to encode the metrics");
+        assert!(encoded.contains("github_commits_total{owner=\"octo-org\",repository=\"exporter\"} 1\n"), "{encoded}");
+        assert!(encoded.contains("github_commits_total{owner=\"octo-org\",repository=\"dashboard\",is_bot=\"true\"} 1\n"), "{encoded}");
+    }
+}
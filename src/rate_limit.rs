@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use chrono::{DateTime, TimeZone, Utc};
+use lazy_static::lazy_static;
+use log::{error, warn};
+use reqwest::header::HeaderMap;
+use reqwest::{Response, StatusCode};
+use crate::metrics::record_rate_limit;
+use crate::now;
+use crate::retry::get_retry_after;
+
+/// Github asks to wait at least a minute after hitting a secondary rate limit without a `Retry-After` header.
+const SECONDARY_RATE_LIMIT_DELAY: Duration = Duration::from_secs(60);
+
+/// How often a request is repeated after waiting for a rate limit. These waits do not count
+/// towards the retries of transient errors, as an exhausted budget is expected to reset.
+pub const MAX_RATE_LIMIT_ATTEMPTS: u32 = 10;
+
+/// The budget of the REST api, which github reports for responses without `X-RateLimit-Resource` as well.
+pub const CORE_RESOURCE: &str = "core";
+
+/// The budget of the GraphQL api, separate from the one of the REST api.
+pub const GRAPHQL_RESOURCE: &str = "graphql";
+
+lazy_static! {
+    static ref RATE_LIMITS: Mutex<HashMap<String, RateLimit>> = Mutex::new(HashMap::new());
+}
+
+/// The rate limit budget of the token for one resource as reported by the last github response.
+#[derive(Debug, Default, Clone, Copy)]
+struct RateLimit {
+    limit: Option<i64>,
+    remaining: Option<i64>,
+    reset: Option<DateTime<Utc>>,
+}
+
+/// Stores the `X-RateLimit-*` headers of a response as the budget of the resource named in
+/// `X-RateLimit-Resource` and exports them as metrics.
+pub fn update_rate_limit(headers: &HeaderMap) {
+    let limit = get_numeric_header(headers, "x-ratelimit-limit");
+    let remaining = get_numeric_header(headers, "x-ratelimit-remaining");
+    let reset = get_numeric_header(headers, "x-ratelimit-reset")
+        .and_then(|timestamp| Utc.timestamp_opt(timestamp, 0).single());
+    if limit.is_none() && remaining.is_none() && reset.is_none() {
+        return;
+    }
+    let resource = headers.get("x-ratelimit-resource")
+        .and_then(|resource| resource.to_str().ok())
+        .unwrap_or(CORE_RESOURCE);
+    let mut rate_limits = match RATE_LIMITS.lock() {
+        Ok(guard) => { guard }
+        Err(_) => {
+            error!("Failed to acquire mutex guard of the rate limit!");
+            return;
+        }
+    };
+    let rate_limit = rate_limits.entry(resource.to_string()).or_default();
+    rate_limit.limit = limit.or(rate_limit.limit);
+    rate_limit.remaining = remaining.or(rate_limit.remaining);
+    rate_limit.reset = reset.or(rate_limit.reset);
+    record_rate_limit(resource, rate_limit.limit, rate_limit.remaining, rate_limit.reset.map(|reset| reset.timestamp()));
+}
+
+/// Sleeps until the rate limit resets if the budget of the token for the resource is exhausted.
+pub async fn wait_for_rate_limit(resource: &str) {
+    if let Some((delay, reset)) = get_exhausted_rate_limit_delay(resource) {
+        warn!("Rate limit of {resource} exhausted, pausing for {seconds}s until {reset}", seconds = delay.as_secs(), reset = reset);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// How long until the budget of the resource resets, `None` unless it is exhausted.
+fn get_exhausted_rate_limit_delay(resource: &str) -> Option<(Duration, DateTime<Utc>)> {
+    let rate_limit = match RATE_LIMITS.lock() {
+        Ok(guard) => { *guard.get(resource)? }
+        Err(_) => {
+            error!("Failed to acquire mutex guard of the rate limit!");
+            return None;
+        }
+    };
+    match (rate_limit.remaining, rate_limit.reset) {
+        (Some(0), Some(reset)) => { (reset - now()).to_std().ok().map(|delay| (delay, reset)) }
+        _ => { None }
+    }
+}
+
+/// Returns how long to wait before repeating the request if the response signals that a
+/// primary or secondary rate limit was hit, `None` if the response is not rate limited.
+pub fn get_rate_limit_delay(response: &Response) -> Option<Duration> {
+    let status = response.status();
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let headers = response.headers();
+    if let Some(retry_after) = get_retry_after(response) {
+        return Some(retry_after);
+    }
+    if get_numeric_header(headers, "x-ratelimit-remaining") == Some(0) {
+        let reset = get_numeric_header(headers, "x-ratelimit-reset")
+            .and_then(|timestamp| Utc.timestamp_opt(timestamp, 0).single());
+        return Some(reset
+            .and_then(|reset| (reset - now()).to_std().ok())
+            .unwrap_or(SECONDARY_RATE_LIMIT_DELAY));
+    }
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => { Some(SECONDARY_RATE_LIMIT_DELAY) }
+        // A 403 without any rate limit information is a genuine permission problem
+        _ => { None }
+    }
+}
+
+fn get_numeric_header(headers: &HeaderMap, name: &str) -> Option<i64> {
+    headers.get(name)?.to_str().ok()?.trim().parse::<i64>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(resource: Option<&'static str>, remaining: i64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(resource) = resource {
+            headers.insert("x-ratelimit-resource", HeaderValue::from_static(resource));
+        }
+        headers.insert("x-ratelimit-limit", HeaderValue::from(30));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from(remaining));
+        headers.insert("x-ratelimit-reset", HeaderValue::from((now() + chrono::Duration::hours(1)).timestamp()));
+        headers
+    }
+
+    #[test]
+    fn only_pauses_the_resource_whose_budget_is_exhausted() {
+        // The search budget is never used by the other tests, so exhausting it does not pause them
+        update_rate_limit(&headers(Some("search"), 0));
+        let (delay, _) = get_exhausted_rate_limit_delay("search").expect("Exhausted budget does not pause");
+        assert!(delay > Duration::from_secs(3500), "{delay:?}");
+        update_rate_limit(&headers(None, 25));
+        assert_eq!(get_exhausted_rate_limit_delay(CORE_RESOURCE), None);
+        assert_eq!(get_exhausted_rate_limit_delay(GRAPHQL_RESOURCE), None);
+    }
+}
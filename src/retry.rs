@@ -0,0 +1,135 @@
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Response, StatusCode};
+use serde::Deserialize;
+use crate::now;
+
+/// How requests failing due to network errors or server errors of github are repeated.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Total number of attempts per request including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry in milliseconds, doubled with every further attempt
+    pub base_delay_ms: u64,
+    /// Upper bound of the delay between two attempts in milliseconds
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 4,
+            base_delay_ms: 500,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Returns how long to wait before the next attempt, `None` once all attempts are used up.
+    /// The exponential delay is jittered by up to half of its length, so that concurrent requests
+    /// failing at the same time do not retry in lockstep. A delay requested by github is followed
+    /// exactly, but the request is given up if github asks for more than the maximum delay, so that
+    /// a failing server can not stall the sync.
+    pub fn get_delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        if let Some(retry_after) = retry_after {
+            return match retry_after <= Duration::from_millis(self.max_delay_ms) {
+                true => { Some(retry_after) }
+                false => { None }
+            };
+        }
+        let exponential = self.base_delay_ms.saturating_mul(1u64 << attempt.saturating_sub(1).min(32));
+        let capped = exponential.min(self.max_delay_ms);
+        let jitter = rand::thread_rng().gen_range(0..=capped / 2);
+        Some(Duration::from_millis(capped - capped / 2 + jitter))
+    }
+}
+
+/// Whether github failed to answer the request due to a temporary problem on its side.
+pub fn is_transient_failure(response: &Response) -> bool {
+    matches!(response.status(), StatusCode::INTERNAL_SERVER_ERROR | StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT)
+}
+
+/// Whether the request failed before github answered it, e.g. due to a lost connection.
+pub fn is_transient_error(error: &reqwest::Error) -> bool {
+    error.is_timeout() || error.is_connect() || error.is_request()
+}
+
+/// The delay requested by the `Retry-After` header.
+pub fn get_retry_after(response: &Response) -> Option<Duration> {
+    parse_retry_after(response.headers().get(RETRY_AFTER)?.to_str().ok()?)
+}
+
+/// `Retry-After` is either a number of seconds or a HTTP date like `Wed, 21 Oct 2015 07:28:00 GMT`.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+    // A date in the past asks to retry right away
+    Some((date - now()).to_std().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, retry_after: Option<&str>) -> Response {
+        let mut response = hyper::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            response = response.header(RETRY_AFTER, retry_after);
+        }
+        Response::from(response.body("").expect("Invalid response"))
+    }
+
+    #[test]
+    fn backs_off_exponentially_with_jitter_up_to_the_maximum() {
+        let config = RetryConfig { max_attempts: 8, base_delay_ms: 500, max_delay_ms: 4_000 };
+        let expected_bounds = [(1, 250, 500), (2, 500, 1_000), (3, 1_000, 2_000), (4, 2_000, 4_000), (7, 2_000, 4_000)];
+        for (attempt, min, max) in expected_bounds {
+            for _ in 0..100 {
+                let delay = config.get_delay(attempt, None).expect("Attempts used up too early").as_millis();
+                assert!((min..=max).contains(&delay), "Delay {delay}ms of attempt {attempt} is outside {min}..={max}ms");
+            }
+        }
+        assert_eq!(config.get_delay(8, None), None);
+    }
+
+    #[test]
+    fn follows_retry_after_up_to_the_maximum_delay() {
+        let config = RetryConfig::default();
+        assert_eq!(config.get_delay(1, Some(Duration::from_secs(2))), Some(Duration::from_secs(2)));
+        assert_eq!(config.get_delay(1, Some(Duration::from_secs(30))), Some(Duration::from_secs(30)));
+        // Retrying earlier than github asked for would only fail again
+        assert_eq!(config.get_delay(1, Some(Duration::from_secs(3600))), None);
+        assert_eq!(config.get_delay(4, Some(Duration::from_secs(2))), None);
+    }
+
+    #[test]
+    fn parses_retry_after_as_seconds_or_date() {
+        assert_eq!(get_retry_after(&response(503, Some("120"))), Some(Duration::from_secs(120)));
+        assert_eq!(get_retry_after(&response(503, Some("Wed, 21 Oct 2015 07:28:00 GMT"))), Some(Duration::ZERO));
+        let in_a_minute = (now() + chrono::Duration::seconds(60)).format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let delay = get_retry_after(&response(503, Some(&in_a_minute))).expect("HTTP date not parsed");
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60), "{delay:?}");
+        assert_eq!(get_retry_after(&response(503, Some("soon"))), None);
+        assert_eq!(get_retry_after(&response(503, None)), None);
+    }
+
+    #[test]
+    fn only_server_errors_are_transient_failures() {
+        for status in [500, 502, 503, 504] {
+            assert!(is_transient_failure(&response(status, None)), "{status} is not transient");
+        }
+        for status in [200, 304, 400, 401, 403, 404, 409, 422, 429, 501] {
+            assert!(!is_transient_failure(&response(status, None)), "{status} is transient");
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use log::debug;
+use crate::config::Target;
+use crate::data::*;
+use crate::error::GitHubError;
+use crate::state::State;
+
+/// Where the repositories and commits are fetched from. The exporter queries github with the
+/// `GitHubClient`, tests use an in-memory fixture instead.
+#[async_trait]
+pub trait GitHubSource: Sync {
+    /// All repositories of the organization or user.
+    async fn list_repositories(&self, target: &Target) -> Result<Vec<MinimalRepository>, GitHubError>;
+
+    /// The commits of the repository committed at or after `since`.
+    async fn list_commits(&self, full_repository_name: &str, since: DateTime<Utc>) -> Result<Vec<Commit>, GitHubError>;
+
+    /// The line statistics and changed files of a single commit.
+    async fn get_commit(&self, full_repository_name: &str, sha: &str) -> Result<CommitChangeDetails, GitHubError>;
+
+    /// How many repositories and commits are fetched at the same time.
+    fn max_concurrent_requests(&self) -> usize;
+
+    /// Fetches the commits of every repository that are newer than its watermark. Sources able to
+    /// fetch the changes along with the history override this to save the request per commit.
+    async fn get_repositories_and_commits_since(&self, repositories: Vec<MinimalRepository>, state: &State) -> Vec<(String, anyhow::Result<RepositoryAndCommits>)> {
+        get_repositories_and_commits_since(self, repositories, state).await
+    }
+
+    /// Called after every successful sync, e.g. to drop cached data that is no longer needed.
+    fn sync_finished(&self) {}
+}
+
+/// Lists the commits of every repository and fetches the changes of each new commit separately.
+pub async fn get_repositories_and_commits_since<Source: GitHubSource + ?Sized>(source: &Source, repositories: Vec<MinimalRepository>, state: &State) -> Vec<(String, anyhow::Result<RepositoryAndCommits>)> {
+    stream::iter(repositories)
+        .map(|repository| async move {
+            let full_name = repository.full_name.clone();
+            (full_name, get_repository_and_commits_since(source, repository, state).await)
+        })
+        .buffer_unordered(source.max_concurrent_requests())
+        .collect()
+        .await
+}
+
+async fn get_repository_and_commits_since<Source: GitHubSource + ?Sized>(source: &Source, repository: MinimalRepository, state: &State) -> anyhow::Result<RepositoryAndCommits> {
+    debug!("Fetching commits for {repo}...", repo=&repository.name);
+    let since = state.get_watermark(&repository.full_name);
+    let commits = source.list_commits(&repository.full_name, since).await?;
+    let full_data = stream::iter(commits)
+        .filter(|commit| futures::future::ready(!state.is_counted(&repository.full_name, &commit.sha)))
+        .map(|commit| {
+            debug!("Fetching details for {commit}...", commit=&commit.commit.message);
+            get_full_commit_data(source, &repository.full_name, commit)
+        })
+        .buffered(source.max_concurrent_requests())
+        .try_collect()
+        .await?;
+    Ok(RepositoryAndCommits::from(repository, full_data))
+}
+
+async fn get_full_commit_data<Source: GitHubSource + ?Sized>(source: &Source, full_repository_name: &str, commit: Commit) -> anyhow::Result<FullCommitData> {
+    let details = source.get_commit(full_repository_name, commit.sha.as_str()).await?;
+    Ok(FullCommitData::from(commit, details))
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::anyhow;
+use chrono::{DateTime, LocalResult, TimeZone, Utc};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use crate::bots::BotDetector;
+use crate::data::RepositoriesWithCommits;
+use crate::identity::IdentityResolver;
+use crate::metrics::{extract_number_of_additions_per_author, extract_number_of_additions_per_repository, extract_number_of_commits_per_author, extract_number_of_commits_per_repository, extract_number_of_deletions_per_author, extract_number_of_deletions_per_repository};
+
+const STATE_FILE_NAME: &str = "state.json";
+
+/// Everything that has to survive a restart: the watermark of every repository and the cumulative
+/// counter values, so that neither the history is refetched nor the counters reset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct State {
+    /// The time of the last successful sync. Repositories persisted before watermarks were
+    /// tracked per repository continue from here.
+    pub last_sync: Option<DateTime<Utc>>,
+    /// Keyed by the full name (`owner/name`) of the repository
+    pub repositories: BTreeMap<String, RepositoryState>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RepositoryState {
+    pub owner: String,
+    pub name: String,
+    /// Without bot commits if they are excluded or labeled
+    pub commits: u64,
+    pub additions: u64,
+    pub deletions: u64,
+    /// The bot commits counted while they were labeled
+    #[serde(default)]
+    pub bot_commits: u64,
+    #[serde(default)]
+    pub bot_additions: u64,
+    #[serde(default)]
+    pub bot_deletions: u64,
+    /// The commit date of the newest commit counted so far
+    #[serde(default)]
+    pub watermark: Option<DateTime<Utc>>,
+    /// The commits committed exactly at the watermark. As `since` is inclusive, github returns
+    /// them again on the next sync, so they have to be skipped to not count them twice.
+    #[serde(default)]
+    pub watermark_commits: Vec<String>,
+    /// Keyed by the author as resolved by the [IdentityResolver]
+    #[serde(default)]
+    pub authors: BTreeMap<String, AuthorState>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuthorState {
+    pub commits: u64,
+    pub additions: u64,
+    pub deletions: u64,
+    /// Whether the commits were labeled as made by a bot
+    #[serde(default)]
+    pub is_bot: bool,
+}
+
+impl State {
+    /// The timestamp from which on the commits of the repository still have to be fetched.
+    pub fn get_watermark(&self, full_repository_name: &str) -> DateTime<Utc> {
+        let repository = match self.repositories.get(full_repository_name) {
+            None => { return initial_watermark(); }
+            Some(repository) => { repository }
+        };
+        match (repository.watermark, self.last_sync) {
+            (Some(watermark), _) => { watermark }
+            // Commits were counted before watermarks were persisted per repository
+            (None, Some(last_sync)) if repository.commits > 0 => { last_sync }
+            _ => { initial_watermark() }
+        }
+    }
+
+    /// Whether the commit has already been counted by a previous sync.
+    pub fn is_counted(&self, full_repository_name: &str, sha: &str) -> bool {
+        self.repositories.get(full_repository_name)
+            .map(|repository| repository.watermark_commits.iter().any(|counted| counted == sha))
+            .unwrap_or(false)
+    }
+
+    /// Adds the commits fetched since the last sync to the cumulative counter values and advances
+    /// the watermark of every repository to the newest commit date actually seen. Bot commits are
+    /// counted like the exported metrics, but the watermarks advance past them in any case.
+    pub fn record(&mut self, data: &RepositoriesWithCommits, identities: &IdentityResolver, bots: &BotDetector) {
+        for (is_bot, part) in bots.partition(data) {
+            let is_bot = is_bot.unwrap_or(false);
+            for (owner, name, commits) in extract_number_of_commits_per_repository(&part) {
+                let repository = self.get_or_create(owner, name);
+                match is_bot {
+                    true => { repository.bot_commits += commits.max(0) as u64; }
+                    false => { repository.commits += commits.max(0) as u64; }
+                }
+            }
+            for (owner, name, additions) in extract_number_of_additions_per_repository(&part) {
+                let repository = self.get_or_create(owner, name);
+                match is_bot {
+                    true => { repository.bot_additions += additions.max(0) as u64; }
+                    false => { repository.additions += additions.max(0) as u64; }
+                }
+            }
+            for (owner, name, deletions) in extract_number_of_deletions_per_repository(&part) {
+                let repository = self.get_or_create(owner, name);
+                match is_bot {
+                    true => { repository.bot_deletions += deletions.max(0) as u64; }
+                    false => { repository.deletions += deletions.max(0) as u64; }
+                }
+            }
+            for (owner, name, author, commits) in extract_number_of_commits_per_author(&part, identities) {
+                let author = self.get_or_create_author(owner, name, author);
+                author.commits += commits.max(0) as u64;
+                author.is_bot |= is_bot;
+            }
+            for (owner, name, author, additions) in extract_number_of_additions_per_author(&part, identities) {
+                self.get_or_create_author(owner, name, author).additions += additions.max(0) as u64;
+            }
+            for (owner, name, author, deletions) in extract_number_of_deletions_per_author(&part, identities) {
+                self.get_or_create_author(owner, name, author).deletions += deletions.max(0) as u64;
+            }
+        }
+        for value in &data.data {
+            let repository = self.get_or_create(value.repository.owner.login.clone(), value.repository.name.clone());
+            for commit in &value.commits {
+                let committed_at = match commit.commit.committed_at() {
+                    None => { continue; }
+                    Some(committed_at) => { committed_at }
+                };
+                if repository.watermark.map(|watermark| committed_at > watermark).unwrap_or(true) {
+                    repository.watermark = Some(committed_at);
+                    repository.watermark_commits.clear();
+                }
+                if repository.watermark == Some(committed_at) {
+                    repository.watermark_commits.push(commit.commit.sha.clone());
+                }
+            }
+        }
+    }
+
+    fn get_or_create(&mut self, owner: String, name: String) -> &mut RepositoryState {
+        self.repositories
+            .entry(format!("{owner}/{name}"))
+            .or_insert_with(|| RepositoryState { owner, name, ..RepositoryState::default() })
+    }
+
+    fn get_or_create_author(&mut self, owner: String, name: String, author: String) -> &mut AuthorState {
+        self.get_or_create(owner, name).authors.entry(author).or_default()
+    }
+}
+
+/// The first sync of a repository fetches everything since before github existed.
+fn initial_watermark() -> DateTime<Utc> {
+    let last = Utc.with_ymd_and_hms(2007, 1, 1, 1, 1, 1);
+    match last {
+        LocalResult::None => { panic!("No timestamp") }
+        LocalResult::Single(dt) => { dt }
+        LocalResult::Ambiguous(_, _) => { panic!("Ambiguous timestamp") }
+    }
+}
+
+/// Reads and writes the [State] as JSON file inside the data directory. Without a data directory
+/// nothing is persisted and every start begins with an empty state.
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    path: Option<PathBuf>,
+}
+
+impl StateStore {
+    pub fn from(data_dir: Option<&Path>) -> anyhow::Result<StateStore> {
+        let path = match data_dir {
+            None => { None }
+            Some(data_dir) => {
+                fs::create_dir_all(data_dir)
+                    .map_err(|e| anyhow!("Failed to create data directory '{dir}': {e}", dir = data_dir.display()))?;
+                Some(data_dir.join(STATE_FILE_NAME))
+            }
+        };
+        Ok(StateStore { path })
+    }
+
+    pub fn load(&self) -> anyhow::Result<State> {
+        let path = match &self.path {
+            Some(path) if path.exists() => { path }
+            _ => { return Ok(State::default()); }
+        };
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read state file '{path}': {e}", path = path.display()))?;
+        let state: State = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("Invalid state file '{path}': {e}", path = path.display()))?;
+        debug!("Loaded state of {count} repositories from {path}", count = state.repositories.len(), path = path.display());
+        Ok(state)
+    }
+
+    /// Writes the state to a temporary file first, so that a crash never leaves a truncated state file behind.
+    pub fn save(&self, state: &State) -> anyhow::Result<()> {
+        let path = match &self.path {
+            None => { return Ok(()); }
+            Some(path) => { path }
+        };
+        let temporary_path = path.with_extension("json.tmp");
+        fs::write(&temporary_path, serde_json::to_string_pretty(state)?)?;
+        fs::rename(&temporary_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::RepositoryAndCommits;
+    use crate::fixture::{commit, repository};
+
+    fn store(test: &str) -> (PathBuf, StateStore) {
+        let data_dir = std::env::temp_dir().join(format!("github-exporter-state-{test}-{pid}", pid = std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        let store = StateStore::from(Some(&data_dir)).expect("Failed to create the data directory");
+        (data_dir, store)
+    }
+
+    #[test]
+    fn saves_and_loads_the_state() {
+        let (data_dir, store) = store("round-trip");
+        assert!(store.load().expect("Missing state file is not empty").repositories.is_empty());
+        let data = RepositoriesWithCommits {
+            data: vec![RepositoryAndCommits::from(repository(1, "octo-org", "exporter"), vec![
+                commit("a1", "2023-05-20T10:00:00Z", 10, 2),
+                commit("a2", "2023-05-21T10:00:00Z", 5, 1),
+            ])],
+        };
+        let mut state = State { last_sync: Some(Utc.with_ymd_and_hms(2023, 5, 22, 8, 0, 0).unwrap()), ..State::default() };
+        state.record(&data, &IdentityResolver::default(), &BotDetector::default());
+        store.save(&state).expect("Failed to save the state");
+        let loaded = store.load().expect("Failed to load the state");
+        assert_eq!(serde_json::to_value(&loaded).unwrap(), serde_json::to_value(&state).unwrap());
+        assert_eq!(loaded.get_watermark("octo-org/exporter"), Utc.with_ymd_and_hms(2023, 5, 21, 10, 0, 0).unwrap());
+        assert!(loaded.is_counted("octo-org/exporter", "a2"));
+        assert!(!data_dir.join("state.json.tmp").exists());
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+
+    #[test]
+    fn loads_state_files_written_before_bots_watermarks_and_authors() {
+        let (data_dir, store) = store("legacy");
+        let legacy = r#"{
+            "last_sync": "2023-05-22T08:00:00Z",
+            "repositories": {
+                "octo-org/exporter": { "owner": "octo-org", "name": "exporter", "commits": 2, "additions": 15, "deletions": 3 }
+            }
+        }"#;
+        fs::write(data_dir.join(STATE_FILE_NAME), legacy).expect("Failed to write the state file");
+        let state = store.load().expect("Failed to load the state");
+        let repository = &state.repositories["octo-org/exporter"];
+        assert_eq!((repository.commits, repository.additions, repository.deletions), (2, 15, 3));
+        assert_eq!((repository.bot_commits, repository.bot_additions, repository.bot_deletions), (0, 0, 0));
+        assert_eq!(repository.watermark, None);
+        assert!(repository.watermark_commits.is_empty());
+        assert!(repository.authors.is_empty());
+        // Continues from the last sync instead of refetching the whole history
+        assert_eq!(state.get_watermark("octo-org/exporter"), Utc.with_ymd_and_hms(2023, 5, 22, 8, 0, 0).unwrap());
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+}
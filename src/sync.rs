@@ -0,0 +1,192 @@
+use std::time::Duration;
+use anyhow::anyhow;
+use log::{debug, error};
+use tokio::time::MissedTickBehavior;
+use crate::bots::BotDetector;
+use crate::config::Target;
+use crate::data::*;
+use crate::filter::RepositoryFilter;
+use crate::identity::IdentityResolver;
+use crate::metrics::record_repository_metrics;
+use crate::now;
+use crate::source::GitHubSource;
+use crate::state::{State, StateStore};
+
+/// What is fetched on every sync, whom the commits are attributed to and which of them are made by bots.
+#[derive(Debug, Clone)]
+pub struct SyncScope {
+    pub targets: Vec<Target>,
+    pub filter: RepositoryFilter,
+    pub identities: IdentityResolver,
+    pub bots: BotDetector,
+}
+
+/// What a sync fetched from github.
+#[derive(Debug, Clone)]
+pub struct SyncResult {
+    /// Every repository matching the filters, including those whose commits could not be fetched
+    pub repositories: Vec<MinimalRepository>,
+    /// The commits committed since the previous sync of the repositories that could be fetched
+    pub data: RepositoriesWithCommits,
+}
+
+/// Refreshes the shared snapshot every `interval`, so that scrapes only read the latest data
+/// instead of querying github themselves.
+/// After every successful sync the state is flushed to the store.
+pub async fn poll_github<Source: GitHubSource>(source: Source, scope: SyncScope, interval: Duration, snapshot: Snapshot, store: StateStore, mut state: State) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        debug!("Refreshing data from github...");
+        let result = match sync(&source, &scope, &mut state).await {
+            None => { continue; }
+            Some(result) => { result }
+        };
+        if let Err(error) = store.save(&state) {
+            error!("Failed to persist the state: {error}");
+        }
+        match snapshot.write() {
+            Ok(mut guard) => { *guard = Some(result.repositories); }
+            Err(_) => { error!("Failed to acquire lock of the snapshot!"); }
+        }
+    }
+}
+
+/// Fetches everything committed since the previous sync, records it in the metrics and advances
+/// the state. Returns `None` and leaves the state untouched if nothing could be fetched.
+pub async fn sync<Source: GitHubSource>(source: &Source, scope: &SyncScope, state: &mut State) -> Option<SyncResult> {
+    let sync_started = now();
+    let result = get_all_commits_since_watermarks(source, &scope.targets, &scope.filter, state).await?;
+    source.sync_finished();
+    record_repository_metrics(&result.data, &scope.identities, &scope.bots);
+    state.record(&result.data, &scope.identities, &scope.bots);
+    state.last_sync = Some(sync_started);
+    Some(result)
+}
+
+/// Fetches the commits of every repository that are newer than its watermark. The watermarks
+/// themselves are only advanced by the caller once the data was fetched completely.
+async fn get_all_commits_since_watermarks<Source: GitHubSource>(source: &Source, targets: &[Target], filter: &RepositoryFilter, state: &State) -> Option<SyncResult> {
+    let result = get_all_commits_since(source, targets, filter, state).await;
+    match result {
+        Ok(value) => { Some(value) }
+        Err(error) => {
+            error!("Some error occurred during fetching of data from github {error}");
+            None
+        }
+    }
+}
+
+/// Repositories that could not be fetched are skipped, so that one failing repository does not
+/// discard the data of all others. Their watermarks stay untouched and they are retried next sync.
+async fn get_all_commits_since<Source: GitHubSource>(source: &Source, targets: &[Target], filter: &RepositoryFilter, state: &State) -> anyhow::Result<SyncResult> {
+    let mut repositories: Vec<MinimalRepository> = Vec::new();
+    let mut listed_targets = 0;
+    for target in targets {
+        let listed_repositories = match source.list_repositories(target).await {
+            Ok(listed_repositories) => { listed_repositories }
+            Err(error) => {
+                error!("Failed to list the repositories of {target}: {error}", target = target.name());
+                continue;
+            }
+        };
+        listed_targets += 1;
+        for repository in listed_repositories {
+            if !filter.matches(&repository) {
+                debug!("Skipping {repo} as it does not match the repository filters", repo = &repository.full_name);
+                continue;
+            }
+            if !repositories.iter().any(|known| known.id == repository.id) {
+                repositories.push(repository);
+            }
+        }
+    }
+    if listed_targets == 0 {
+        return Err(anyhow!("Failed to list the repositories of every organization and user"));
+    }
+    let results = source.get_repositories_and_commits_since(repositories.clone(), state).await;
+    let mut data = Vec::new();
+    for (full_name, result) in results {
+        match result {
+            Ok(repository_and_commits) => { data.push(repository_and_commits); }
+            Err(error) => { error!("Failed to fetch the commits of {repo}, retrying next sync: {error}", repo = full_name); }
+        }
+    }
+    Ok(SyncResult { repositories, data: RepositoriesWithCommits { data } })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterConfig;
+    use crate::fixture::{commit, repository, FixtureSource};
+
+    fn scope(filter: FilterConfig) -> SyncScope {
+        SyncScope {
+            targets: vec![Target::Organization(String::from("octo-org"))],
+            filter: RepositoryFilter::from(&filter).expect("Invalid filter"),
+            identities: IdentityResolver::default(),
+            bots: BotDetector::default(),
+        }
+    }
+
+    fn source() -> FixtureSource {
+        FixtureSource::default()
+            .with_repository(repository(1, "octo-org", "exporter"))
+            .with_repository(repository(2, "octo-org", "legacy-dashboard"))
+            .with_commit("octo-org/exporter", commit("a1", "2023-05-20T10:00:00Z", 10, 2))
+            .with_commit("octo-org/exporter", commit("a2", "2023-05-21T10:00:00Z", 5, 1))
+            .with_commit("octo-org/legacy-dashboard", commit("b1", "2023-05-19T08:00:00Z", 7, 7))
+    }
+
+    #[tokio::test]
+    async fn first_sync_counts_every_commit() {
+        let source = source();
+        let mut state = State::default();
+        let result = sync(&source, &scope(FilterConfig::default()), &mut state).await.expect("Sync failed");
+        assert_eq!(result.data.data.len(), 2);
+        let exporter = &state.repositories["octo-org/exporter"];
+        assert_eq!((exporter.commits, exporter.additions, exporter.deletions), (2, 15, 3));
+        assert_eq!(exporter.watermark_commits, vec![String::from("a2")]);
+        let legacy = &state.repositories["octo-org/legacy-dashboard"];
+        assert_eq!((legacy.commits, legacy.additions, legacy.deletions), (1, 7, 7));
+        assert!(state.last_sync.is_some());
+        assert_eq!(source.requested_commits(), 3);
+    }
+
+    #[tokio::test]
+    async fn second_sync_does_not_count_commits_again() {
+        let source = source();
+        let mut state = State::default();
+        sync(&source, &scope(FilterConfig::default()), &mut state).await.expect("First sync failed");
+        let result = sync(&source, &scope(FilterConfig::default()), &mut state).await.expect("Second sync failed");
+        assert!(result.data.data.iter().all(|repository| repository.commits.is_empty()));
+        let exporter = &state.repositories["octo-org/exporter"];
+        assert_eq!((exporter.commits, exporter.additions, exporter.deletions), (2, 15, 3));
+        assert_eq!(source.requested_commits(), 3);
+    }
+
+    #[tokio::test]
+    async fn failing_repository_keeps_the_data_of_the_others() {
+        let source = source().with_failing_repository("octo-org/legacy-dashboard");
+        let mut state = State::default();
+        let result = sync(&source, &scope(FilterConfig::default()), &mut state).await.expect("Sync failed");
+        assert_eq!(result.data.data.len(), 1);
+        assert_eq!(result.data.data[0].repository.full_name, "octo-org/exporter");
+        assert!(!state.repositories.contains_key("octo-org/legacy-dashboard"));
+        // The repository still exists, so it is still counted
+        assert_eq!(result.repositories.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn excluded_repositories_are_not_fetched() {
+        let source = source();
+        let mut state = State::default();
+        let config = FilterConfig { exclude: vec![String::from("legacy-*")], ..FilterConfig::default() };
+        let result = sync(&source, &scope(config), &mut state).await.expect("Sync failed");
+        assert_eq!(result.data.data.len(), 1);
+        assert_eq!(result.repositories.len(), 1);
+        assert_eq!(source.requested_commits(), 2);
+    }
+}
@@ -0,0 +1,79 @@
+//! Test helpers shared by the unit tests of the library (through `src/fixture.rs`) and the
+//! integration tests: recorded github responses adjusted per test and a local server replaying them.
+
+use std::convert::Infallible;
+use std::net::TcpListener;
+use std::sync::Arc;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+
+const REPOSITORY: &str = include_str!("../fixtures/repository.json");
+const COMMIT: &str = include_str!("../fixtures/commit.json");
+const COMMIT_CHANGES: &str = include_str!("../fixtures/commit_changes.json");
+
+/// A repository of the owner, based on a response of the github repository list.
+pub fn repository(id: i32, owner: &str, name: &str) -> Value {
+    let mut repository: Value = serde_json::from_str(REPOSITORY).expect("Invalid repository fixture");
+    repository["id"] = json!(id);
+    repository["name"] = json!(name);
+    repository["full_name"] = json!(format!("{owner}/{name}"));
+    repository["owner"]["login"] = json!(owner);
+    repository
+}
+
+/// A commit committed at `date` (RFC 3339), based on a response of the github commit list.
+pub fn commit(sha: &str, date: &str) -> Value {
+    let mut commit: Value = serde_json::from_str(COMMIT).expect("Invalid commit fixture");
+    commit["sha"] = json!(sha);
+    commit["commit"]["author"]["date"] = json!(date);
+    commit["commit"]["committer"]["date"] = json!(date);
+    commit
+}
+
+/// The details of a single commit with the given line statistics.
+pub fn commit_changes(sha: &str, additions: i32, deletions: i32) -> Value {
+    let mut changes: Value = serde_json::from_str(COMMIT_CHANGES).expect("Invalid commit changes fixture");
+    changes["sha"] = json!(sha);
+    changes["stats"] = json!({ "additions": additions, "deletions": deletions, "total": additions + deletions });
+    changes
+}
+
+pub fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(body.to_string()))
+        .expect("Invalid response")
+}
+
+/// Starts a server answering every request with `respond`, which gets the base url of the server
+/// to build links with and the request along with its body. Returns the base url.
+pub fn start_mock_server<Respond>(respond: Respond) -> String
+where Respond: Fn(&str, &Request<String>) -> Response<Body> + Send + Sync + 'static {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind the mock server");
+    listener.set_nonblocking(true).expect("Failed to bind the mock server");
+    let base_url = format!("http://{address}", address = listener.local_addr().expect("Failed to bind the mock server"));
+    let respond = Arc::new(respond);
+    let service_base_url = base_url.clone();
+    let make_service = make_service_fn(move |_| {
+        let base_url = service_base_url.clone();
+        let respond = respond.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let base_url = base_url.clone();
+                let respond = respond.clone();
+                async move {
+                    let (parts, body) = request.into_parts();
+                    let body = hyper::body::to_bytes(body).await
+                        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                        .unwrap_or_default();
+                    Ok::<_, Infallible>(respond(&base_url, &Request::from_parts(parts, body)))
+                }
+            }))
+        }
+    });
+    let server = Server::from_tcp(listener).expect("Failed to start the mock server").serve(make_service);
+    tokio::spawn(server);
+    base_url
+}
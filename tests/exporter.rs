@@ -0,0 +1,162 @@
+//! Runs the exporter binary against a local server replaying recorded github responses and checks
+//! the metrics it exports.
+
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use hyper::header::HeaderValue;
+use hyper::{Body, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use common::{commit, commit_changes, repository, start_mock_server};
+
+mod common;
+
+const TOKEN: &str = "test-token";
+
+/// The exporter process along with its working directory, both removed once the test is done.
+struct Exporter {
+    process: Child,
+    directory: PathBuf,
+    address: SocketAddr,
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.directory);
+    }
+}
+
+impl Exporter {
+    fn start(name: &str, api_url: &str, token: &str) -> Exporter {
+        let directory = std::env::temp_dir().join(format!("github-exporter-{name}-{pid}", pid = std::process::id()));
+        // The exporter writes its log file relative to the working directory
+        fs::create_dir_all(directory.join("logs")).expect("Failed to create the working directory");
+        let address = get_free_address();
+        let process = Command::new(env!("CARGO_BIN_EXE_github-exporter-arm64-rs"))
+            .current_dir(&directory)
+            .env_clear()
+            .env("ORG", "octo-org")
+            .env("TOKEN", token)
+            .env("GITHUB_API_URL", api_url)
+            .env("LISTEN_ADDR", address.ip().to_string())
+            .env("PORT", address.port().to_string())
+            .env("POLL_INTERVAL", "3600")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start the exporter");
+        Exporter { process, directory, address }
+    }
+
+    async fn get(&self, path: &str) -> Option<(u16, String)> {
+        let response = reqwest::get(format!("http://{address}{path}", address = self.address)).await.ok()?;
+        let status = response.status().as_u16();
+        Some((status, response.text().await.ok()?))
+    }
+
+    /// Polls the metrics until `line` shows up, the first sync runs in the background after the start.
+    async fn wait_for_metric(&self, line: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            if let Some((200, metrics)) = self.get("/metrics").await {
+                if metrics.lines().any(|actual| actual == line) {
+                    return metrics;
+                }
+                if Instant::now() > deadline {
+                    panic!("Metric '{line}' is missing in:\n{metrics}");
+                }
+            } else if Instant::now() > deadline {
+                panic!("The exporter did not start serving metrics");
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+fn get_free_address() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to find a free port");
+    listener.local_addr().expect("Failed to find a free port")
+}
+
+/// Starts a server answering like the github REST api for the organization `octo-org`, returning its base url.
+fn start_mock_github() -> String {
+    start_mock_server(respond)
+}
+
+fn respond(base_url: &str, request: &Request<String>) -> Response<Body> {
+    let authorization = request.headers().get("Authorization").and_then(|value| value.to_str().ok());
+    if authorization != Some(format!("Bearer {TOKEN}").as_str()) {
+        return json_response(StatusCode::UNAUTHORIZED, json!({ "message": "Bad credentials" }));
+    }
+    let is_second_page = request.uri().query().map(|query| query.contains("page=2")).unwrap_or(false);
+    match request.uri().path() {
+        "/orgs/octo-org/repos" if is_second_page => {
+            json_response(StatusCode::OK, json!([repository(2, "octo-org", "empty"), repository(3, "octo-org", "restricted")]))
+        }
+        "/orgs/octo-org/repos" => {
+            let mut response = json_response(StatusCode::OK, json!([repository(1, "octo-org", "exporter")]));
+            let link = format!("<{base_url}/orgs/octo-org/repos?per_page=100&page=2>; rel=\"next\", <{base_url}/orgs/octo-org/repos?per_page=100&page=2>; rel=\"last\"");
+            response.headers_mut().insert("Link", link.parse().expect("Invalid link header"));
+            response
+        }
+        "/repos/octo-org/exporter/commits" => {
+            json_response(StatusCode::OK, json!([commit("c2", "2023-05-21T10:00:00Z"), commit("c1", "2023-05-20T10:00:00Z")]))
+        }
+        "/repos/octo-org/exporter/commits/c1" => { json_response(StatusCode::OK, commit_changes("c1", 10, 2)) }
+        "/repos/octo-org/exporter/commits/c2" => { json_response(StatusCode::OK, commit_changes("c2", 5, 1)) }
+        "/repos/octo-org/empty/commits" => {
+            json_response(StatusCode::CONFLICT, json!({ "message": "Git Repository is empty." }))
+        }
+        _ => { json_response(StatusCode::NOT_FOUND, json!({ "message": "Not Found" })) }
+    }
+}
+
+/// Like github, every response carries the rate limit budget of the token.
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    let mut response = common::json_response(status, body);
+    let headers = response.headers_mut();
+    headers.insert("x-ratelimit-limit", HeaderValue::from_static("5000"));
+    headers.insert("x-ratelimit-remaining", HeaderValue::from_static("4990"));
+    headers.insert("x-ratelimit-reset", HeaderValue::from_static("1700000000"));
+    headers.insert("x-ratelimit-resource", HeaderValue::from_static("core"));
+    response
+}
+
+#[tokio::test]
+async fn exports_commits_and_lines_per_repository() {
+    let api_url = start_mock_github();
+    let exporter = Exporter::start("sync", &api_url, TOKEN);
+    let metrics = exporter.wait_for_metric("github_commits_total{owner=\"octo-org\",repository=\"exporter\"} 2").await;
+    let expected = [
+        "github_lines_added_total{owner=\"octo-org\",repository=\"exporter\"} 15",
+        "github_lines_deleted_total{owner=\"octo-org\",repository=\"exporter\"} 3",
+        "github_commits_by_author_total{owner=\"octo-org\",repository=\"exporter\",author=\"octocat\"} 2",
+        "github_lines_added_by_author_total{owner=\"octo-org\",repository=\"exporter\",author=\"octocat\"} 15",
+        // Empty repositories are exported, but repositories that failed are retried next sync
+        "github_commits_total{owner=\"octo-org\",repository=\"empty\"} 0",
+        // Repositories that failed still exist and are counted
+        "repositoryCount{owner=\"octo-org\"} 3",
+        "github_exporter_api_errors_total{endpoint=\"list_commits\",kind=\"not_found\"} 1",
+        "github_exporter_rate_limit_limit{resource=\"core\"} 5000",
+        "github_exporter_rate_limit_remaining{resource=\"core\"} 4990",
+        "github_exporter_rate_limit_reset_timestamp{resource=\"core\"} 1700000000",
+    ];
+    for line in expected {
+        assert!(metrics.lines().any(|actual| actual == line), "Metric '{line}' is missing in:\n{metrics}");
+    }
+    assert!(!metrics.contains("repository=\"restricted\""), "Failed repository is exported:\n{metrics}");
+    assert_eq!(exporter.get("/ready").await.map(|(status, _)| status), Some(200));
+}
+
+#[tokio::test]
+async fn reports_rejected_token_without_becoming_ready() {
+    let api_url = start_mock_github();
+    let exporter = Exporter::start("unauthorized", &api_url, "revoked-token");
+    let metrics = exporter.wait_for_metric("github_exporter_api_errors_total{endpoint=\"list_repositories\",kind=\"unauthorized\"} 1").await;
+    assert!(!metrics.contains("github_commits_total{"), "Commits are exported without a sync:\n{metrics}");
+    assert_eq!(exporter.get("/ready").await.map(|(status, _)| status), Some(503));
+}